@@ -1,3 +1,30 @@
+pub(crate) mod accept;
+pub mod access_log;
+#[cfg(feature = "auth")]
+pub mod auth;
+pub mod authz;
+pub mod cache;
+#[cfg(feature = "compression")]
+pub mod compression;
+#[cfg(feature = "cookies")]
+pub mod cookie;
+pub mod cors;
+pub mod error;
 pub mod handler;
+pub mod health;
+pub mod limits;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod middleware;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod panic;
+pub mod problem;
+pub mod rate_limit;
+pub mod request_id;
+pub mod response;
 pub mod router;
-pub mod server;
\ No newline at end of file
+pub mod server;
+pub mod static_files;
+pub mod trace;
+pub mod validate;
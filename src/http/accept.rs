@@ -0,0 +1,29 @@
+/**
+ * q-values of the content codings listed in an `Accept-Encoding` header.
+ * names are lowercased, `x-gzip` counts as `gzip`.
+ **/
+pub(crate) struct AcceptEncoding(Vec<(String, f32)>);
+
+impl AcceptEncoding {
+    pub(crate) fn parse(accept: &str) -> Self {
+        AcceptEncoding(accept.split(',').filter_map(|part| {
+            let mut it = part.split(';');
+            let name = match it.next()?.trim().to_ascii_lowercase().as_str() {
+                "x-gzip" => "gzip".to_string(),
+                name => name.to_string(),
+            };
+            let q = it.filter_map(|p| p.trim().strip_prefix("q=").map(|q| q.trim().parse::<f32>().unwrap_or(0.0)))
+                .next().unwrap_or(1.0);
+            (!name.is_empty()).then_some((name, q))
+        }).collect())
+    }
+
+    // Weight of `coding`, from its own entry or else from `*`, 0 when not accepted.
+    pub(crate) fn weight(self: &Self, coding: &str) -> f32 {
+        self.0.iter()
+            .find(|(name, _)| name == coding)
+            .or_else(|| self.0.iter().find(|(name, _)| name == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0)
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use chrono::{DateTime, Local, SecondsFormat};
+use hyper::body::HttpBody;
+use hyper::{header, Body, Request, Response};
+use log::info;
+use serde::Serialize;
+
+use crate::http::middleware::{MatchedRoute, Middleware, Next};
+use crate::http::request_id::RequestId;
+use crate::http::server::PeerAddr;
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+pub enum Format {
+    // NCSA common log format
+    Common,
+    // common log format followed by referer and user agent
+    Combined,
+    // one json object per line carrying every recorded field
+    Json,
+}
+
+pub trait AccessLogSink: Send + Sync {
+    fn write(self: &Self, line: &str);
+}
+
+impl<F> AccessLogSink for F where F: Fn(&str) + Send + Sync {
+    fn write(self: &Self, line: &str) {
+        (self)(line)
+    }
+}
+
+// Writes through the `log` crate at info level under the "access" target.
+pub struct LogSink;
+
+impl AccessLogSink for LogSink {
+    fn write(self: &Self, line: &str) {
+        info!(target: "access", "{}", line);
+    }
+}
+
+#[derive(Serialize)]
+struct Entry {
+    #[serde(skip)]
+    received: DateTime<Local>,
+    time: String,
+    method: String,
+    path: String,
+    version: String,
+    route: Option<String>,
+    status: u16,
+    bytes: Option<u64>,
+    latency_ms: f64,
+    peer: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+}
+
+/**
+ * middleware writing one line per request once the response is produced
+ **/
+pub struct AccessLog {
+    format: Format,
+    sink: Box<dyn AccessLogSink>,
+    sample_rate: f64,
+    suppressed: Vec<String>,
+    seen: AtomicU64,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessLog {
+    pub fn new() -> Self {
+        AccessLog {
+            format: Format::Combined,
+            sink: Box::new(LogSink),
+            sample_rate: 1.0,
+            suppressed: Vec::new(),
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    pub fn format(mut self: Self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn sink(mut self: Self, sink: impl AccessLogSink + 'static) -> Self {
+        self.sink = Box::new(sink);
+        self
+    }
+
+    // Keep only this fraction of the lines, spread evenly over the requests.
+    pub fn sample(mut self: Self, rate: f64) -> Self {
+        self.sample_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    // Never log requests whose matched route pattern or raw path equals `route`.
+    pub fn suppress(mut self: Self, route: &str) -> Self {
+        self.suppressed.push(route.to_string());
+        self
+    }
+
+    fn sampled(self: &Self) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        (n * self.sample_rate).floor() != ((n + 1.0) * self.sample_rate).floor()
+    }
+
+    fn render(self: &Self, e: &Entry) -> String {
+        let dash = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".to_string());
+        let common = format!("{} - - [{}] \"{} {} {}\" {} {}",
+                             dash(&e.peer),
+                             e.received.format("%d/%b/%Y:%H:%M:%S %z"),
+                             e.method, e.path, e.version, e.status,
+                             e.bytes.map(|b| b.to_string()).unwrap_or_else(|| "-".to_string()));
+        match self.format {
+            Format::Common => common,
+            Format::Combined => format!("{} \"{}\" \"{}\"", common, dash(&e.referer), dash(&e.user_agent)),
+            Format::Json => serde_json::to_string(e).unwrap(),
+        }
+    }
+}
+
+fn header_value(req: &HyperReq, name: header::HeaderName) -> Option<String> {
+    req.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string())
+}
+
+#[async_trait::async_trait]
+impl Middleware for AccessLog {
+    async fn handle(self: &Self, req: HyperReq, next: Next<'_>) -> hyper::Result<HyperResp> {
+        let route = req.extensions().get::<MatchedRoute>().map(|r| r.0.clone());
+        let path = req.uri().path().to_string();
+        if self.suppressed.iter().any(|s| *s == path || Some(s) == route.as_ref()) || !self.sampled() {
+            return next.run(req).await;
+        }
+        let begin = Instant::now();
+        let received = Local::now();
+        let mut entry = Entry {
+            received,
+            time: received.to_rfc3339_opts(SecondsFormat::Millis, false),
+            method: req.method().to_string(),
+            path: req.uri().path_and_query().map(|p| p.to_string()).unwrap_or(path),
+            version: format!("{:?}", req.version()),
+            route,
+            status: 0,
+            bytes: None,
+            latency_ms: 0.0,
+            peer: req.extensions().get::<PeerAddr>().map(|p| p.0.ip().to_string()),
+            referer: header_value(&req, header::REFERER),
+            user_agent: header_value(&req, header::USER_AGENT),
+            request_id: req.extensions().get::<RequestId>().map(|r| r.0.clone()),
+        };
+        let res = next.run(req).await;
+        if let Ok(resp) = &res {
+            // set by a RequestIds middleware wrapped inside this one
+            if entry.request_id.is_none() {
+                entry.request_id = resp.extensions().get::<RequestId>().map(|r| r.0.clone());
+            }
+            entry.status = resp.status().as_u16();
+            entry.bytes = resp.body().size_hint().exact();
+            entry.latency_ms = begin.elapsed().as_secs_f64() * 1000.0;
+            self.sink.write(&self.render(&entry));
+        }
+        res
+    }
+}
+
+
+#[tokio::test]
+async fn test_access_log() {
+    use std::sync::{Arc, Mutex};
+    use hyper::Method;
+    use crate::http::handler::{Filter, GET};
+    use crate::http::router::Router;
+    let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+    let out = lines.clone();
+    let mut r = Router::new();
+    r.add(GET().start_with("/hello").handle_request().then(|_| "world").ok());
+    r.add(GET().eq("/ping").handle_request().then(|_| "pong").ok());
+    r.wrap(AccessLog::new()
+        .format(Format::Json)
+        .suppress("/ping")
+        .sample(0.5)
+        .sink(move |line: &str| out.lock().unwrap().push(line.to_string())));
+    // inside the access log, so the id comes back with the response
+    r.wrap(crate::http::request_id::RequestIds::new().header("X-Correlation-Id").unwrap());
+    for _ in 0..4 {
+        let req = Request::get("/hello/x").header(header::USER_AGENT, "curl").header("x-correlation-id", "c-1").body(Body::empty()).unwrap();
+        r.process(Method::GET, "/hello/x".to_string(), req).await.unwrap();
+        let req = Request::get("/ping").body(Body::empty()).unwrap();
+        r.process(Method::GET, "/ping".to_string(), req).await.unwrap();
+    }
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 2);
+    let v: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+    assert_eq!(v["route"], "/hello*");
+    assert_eq!(v["status"], 200);
+    assert_eq!(v["bytes"], 5);
+    assert_eq!(v["user_agent"], "curl");
+    assert_eq!(v["request_id"], "c-1");
+}
@@ -0,0 +1,225 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use hyper::header;
+use hyper::{Body, Method, Request};
+use serde_json::Value;
+
+use crate::http::error::HttpError;
+use crate::http::handler::{Filter, Select};
+
+type HyperReq = Request<Body>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Permission {
+    Scope(String),
+    Role(String),
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permission::Scope(s) => write!(f, "scope {}", s),
+            Permission::Role(r) => write!(f, "role {}", r),
+        }
+    }
+}
+
+/**
+ * authenticated principal, put in the request extensions by the
+ * authentication stages. `P` is the type produced by the authenticator.
+ **/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal<P>(pub P);
+
+pub fn principal<P: Send + Sync + 'static>(req: &HyperReq) -> Option<&P> {
+    req.extensions().get::<Principal<P>>().map(|p| &p.0)
+}
+
+// Scopes and roles of a principal, checked by the route guards.
+pub trait Permissions {
+    fn scopes(self: &Self) -> Vec<String>;
+    fn roles(self: &Self) -> Vec<String>;
+}
+
+fn strings(value: Option<&Value>) -> Vec<String> {
+    match value {
+        Some(Value::String(s)) => s.split_whitespace().map(|s| s.to_string()).collect(),
+        Some(Value::Array(a)) => a.iter().filter_map(|v| v.as_str()).map(|s| s.to_string()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// JWT claims: `scope` (space separated) or `scp`, and `roles`.
+impl Permissions for Value {
+    fn scopes(self: &Self) -> Vec<String> {
+        let mut scopes = strings(self.get("scope"));
+        scopes.extend(strings(self.get("scp")));
+        scopes
+    }
+
+    fn roles(self: &Self) -> Vec<String> {
+        strings(self.get("roles"))
+    }
+}
+
+/**
+ * what the authenticated principal is granted, put in the request
+ * extensions by the Authenticate middleware.
+ **/
+#[derive(Clone, Debug, Default)]
+pub struct Grants {
+    scopes: HashSet<String>,
+    roles: HashSet<String>,
+}
+
+impl Grants {
+    pub fn of(principal: &impl Permissions) -> Self {
+        Grants {
+            scopes: principal.scopes().into_iter().collect(),
+            roles: principal.roles().into_iter().collect(),
+        }
+    }
+
+    pub fn has(self: &Self, permission: &Permission) -> bool {
+        match permission {
+            Permission::Scope(s) => self.scopes.contains(s),
+            Permission::Role(r) => self.roles.contains(r),
+        }
+    }
+}
+
+// Why authentication failed, answered by guarded routes instead of a bare 401.
+#[derive(Clone, Debug)]
+pub(crate) struct AuthFailure(pub HttpError);
+
+// Why the request is refused by a route requiring `required`, if it is.
+pub(crate) fn denied(required: &[Permission], req: &HyperReq) -> Option<HttpError> {
+    if required.is_empty() {
+        return None;
+    }
+    let grants = match req.extensions().get::<Grants>() {
+        Some(grants) => grants,
+        None => return Some(match req.extensions().get::<AuthFailure>() {
+            Some(AuthFailure(e)) => e.clone(),
+            None => HttpError::unauthorized("authentication required"),
+        }),
+    };
+    match required.iter().find(|p| !grants.has(p))? {
+        Permission::Scope(scope) => Some(HttpError::forbidden(&format!("missing scope {}", scope))
+            .header(header::WWW_AUTHENTICATE,
+                    &format!("Bearer error=\"insufficient_scope\", scope=\"{}\"", scope))),
+        missing => Some(HttpError::forbidden(&format!("missing {}", missing))),
+    }
+}
+
+// Filter requiring permissions, see Filter::require_scope and Filter::require_role.
+pub struct Guarded<F> {
+    pub(crate) inner: F,
+    pub(crate) required: Permission,
+}
+
+impl<F: Filter> Select for Guarded<F> {
+    fn method(self: &Self) -> Method {
+        self.inner.method()
+    }
+
+    fn methods(self: &Self) -> Vec<Method> {
+        self.inner.methods()
+    }
+}
+
+impl<F: Filter> Filter for Guarded<F> {
+    fn test(self: &Self, path: &str) -> bool {
+        self.inner.test(path)
+    }
+    fn pattern(self: &Self) -> &str {
+        self.inner.pattern()
+    }
+    fn permissions(self: &Self) -> Vec<Permission> {
+        let mut permissions = self.inner.permissions();
+        permissions.push(self.required.clone());
+        permissions
+    }
+}
+
+// A route as listed by Router::routes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteInfo {
+    pub method: Method,
+    pub pattern: String,
+    pub permissions: Vec<Permission>,
+}
+
+
+#[cfg(feature = "auth")]
+#[tokio::test]
+async fn test_guards() {
+    use hyper::StatusCode;
+    use serde_json::json;
+    use crate::http::auth::{Authenticate, Jwt, JwtValidator};
+    use crate::http::handler::GET;
+    use crate::http::router::Router;
+
+    let mut r = Router::new();
+    r.add(GET().eq("/admin").require_scope("admin:read").handle_request().then(|_| "admin").ok());
+    r.add(GET().eq("/ops").require_role("ops").require_scope("admin:read").handle_request().then(|_| "ops").ok());
+    r.add(GET().eq("/public").handle_request().then(|_| "public").ok());
+    r.wrap(Authenticate::new(Jwt::<Value>::new(JwtValidator::hmac(b"secret"))));
+
+    let token = |claims: Value| jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims,
+                                                     &jsonwebtoken::EncodingKey::from_secret(b"secret")).unwrap();
+    let exp = chrono::Utc::now().timestamp() + 60;
+    let call = |path: &'static str, token: Option<String>| {
+        let mut req = Request::get(path);
+        if let Some(t) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", t));
+        }
+        r.process(Method::GET, path.to_string(), req.body(Body::empty()).unwrap())
+    };
+
+    let res = call("/public", None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = call("/admin", None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    let res = call("/admin", Some("garbage".to_string())).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let reader = token(json!({"sub": "a", "exp": exp, "scope": "admin:read other"}));
+    let res = call("/admin", Some(reader.clone())).await.unwrap();
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "admin");
+    let res = call("/ops", Some(reader)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "missing role ops");
+
+    let writer = token(json!({"sub": "b", "exp": exp, "scp": ["admin:write"], "roles": ["ops"]}));
+    let res = call("/admin", Some(writer.clone())).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer error=\"insufficient_scope\", scope=\"admin:read\"");
+
+    let mut routes = r.routes();
+    routes.sort_by(|a, b| a.pattern.cmp(&b.pattern));
+    assert_eq!(routes, vec![
+        RouteInfo {
+            method: Method::GET,
+            pattern: "/admin".to_string(),
+            permissions: vec![Permission::Scope("admin:read".to_string())],
+        },
+        RouteInfo {
+            method: Method::GET,
+            pattern: "/ops".to_string(),
+            permissions: vec![Permission::Role("ops".to_string()), Permission::Scope("admin:read".to_string())],
+        },
+        RouteInfo { method: Method::GET, pattern: "/public".to_string(), permissions: vec![] },
+    ]);
+
+    // guards are checked before the pipeline, a stage can't provide their grants
+    assert!(std::panic::catch_unwind(|| {
+        GET().eq("/stage").require_scope("admin:read").handle_request().require_jwt::<Value>(JwtValidator::hmac(b"secret"))
+    }).is_err());
+
+    // a guarded filter keeps every method of the one it wraps
+    let files = crate::http::static_files::static_files(std::env::temp_dir()).mount("/files").require_role("ops");
+    assert_eq!(files.methods(), vec![Method::GET, Method::HEAD]);
+}
@@ -0,0 +1,289 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::time::Instant;
+
+use crate::http::middleware::{MatchedRoute, Middleware, Next};
+use crate::pipeline::cache::Lru;
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+// Directives of the Cache-Control headers, lowercased, with their value if any.
+fn cache_control(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers.get_all(header::CACHE_CONTROL).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|d| {
+            let mut it = d.splitn(2, '=');
+            let name = it.next()?.trim().to_ascii_lowercase();
+            let value = it.next().map(|v| v.trim().trim_matches('"').to_string());
+            (!name.is_empty()).then_some((name, value))
+        })
+        .collect()
+}
+
+fn has(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives.iter().any(|(n, _)| n == name)
+}
+
+fn seconds(directives: &[(String, Option<String>)], name: &str) -> Option<Duration> {
+    directives.iter()
+        .find(|(n, _)| n == name)
+        .and_then(|(_, v)| v.as_ref()?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+struct Stored {
+    // request headers named by the response Vary, with the values they had
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl Stored {
+    fn matches(self: &Self, headers: &HeaderMap) -> bool {
+        self.vary.iter().all(|(name, value)| headers.get(name) == value.as_ref())
+    }
+
+    fn response(self: &Self) -> HyperResp {
+        let mut res = Response::new(Body::from(self.body.clone()));
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers.clone();
+        res.headers_mut().insert(header::AGE, HeaderValue::from(self.stored.elapsed().as_secs()));
+        res
+    }
+}
+
+/**
+ * middleware caching the 200 responses of GET requests in memory, shared by all clients.
+ * a response is kept for its `s-maxage` or `max-age`, or the default ttl when it has
+ * neither, one variant per value of the request headers its `Vary` names.
+ * responses with `no-store`, `no-cache`, `private`, `Vary: *` or a `Set-Cookie` are never
+ * kept, nor the ones to requests with `Authorization` unless they are `public` or have `s-maxage`.
+ * requests with `no-store` bypass the cache, `no-cache` or `max-age=0` refresh it.
+ **/
+pub struct ResponseCache {
+    store: Mutex<Lru<String, Vec<Stored>>>,
+    default_ttl: Option<Duration>,
+    max_body: usize,
+    skipped: Vec<String>,
+}
+
+impl ResponseCache {
+    // Keeps the responses of at most `capacity` URIs.
+    pub fn new(capacity: usize) -> Self {
+        ResponseCache {
+            store: Mutex::new(Lru::new(capacity)),
+            default_ttl: None,
+            max_body: 1024 * 1024,
+            skipped: Vec::new(),
+        }
+    }
+
+    // Ttl of responses without max-age, they are not kept when there is none.
+    pub fn default_ttl(mut self: Self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    // Responses with a larger body, or without a known size, are not kept.
+    pub fn max_body(mut self: Self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+
+    // Never cache the route with this pattern.
+    pub fn skip(mut self: Self, route: &str) -> Self {
+        self.skipped.push(route.to_string());
+        self
+    }
+
+    fn lookup(self: &Self, key: &String, req: &HyperReq) -> Option<HyperResp> {
+        let mut store = self.store.lock().unwrap();
+        let variants = store.get(key)?;
+        let now = Instant::now();
+        variants.retain(|v| v.expires > now);
+        variants.iter().find(|v| v.matches(req.headers())).map(Stored::response)
+    }
+
+    // How long the response may be kept, None when it may not.
+    fn ttl(self: &Self, req_headers: &HeaderMap, res: &HyperResp) -> Option<Duration> {
+        if res.status() != StatusCode::OK {
+            return None;
+        }
+        let directives = cache_control(res.headers());
+        if ["no-store", "no-cache", "private"].iter().any(|d| has(&directives, d)) {
+            return None;
+        }
+        // the cookies of one client would be replayed to every other
+        if res.headers().contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        let shared = seconds(&directives, "s-maxage");
+        if req_headers.contains_key(header::AUTHORIZATION) && shared.is_none() && !has(&directives, "public") {
+            return None;
+        }
+        let size_ok = res.body().size_hint().exact().is_some_and(|s| s as usize <= self.max_body);
+        if !size_ok {
+            return None;
+        }
+        shared.or_else(|| seconds(&directives, "max-age")).or(self.default_ttl).filter(|ttl| !ttl.is_zero())
+    }
+
+    fn vary(req_headers: &HeaderMap, res: &HyperResp) -> Option<Vec<(HeaderName, Option<HeaderValue>)>> {
+        let mut vary = Vec::new();
+        for name in res.headers().get_all(header::VARY).iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|n| n.trim())
+            .filter(|n| !n.is_empty()) {
+            if name == "*" {
+                return None;
+            }
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            let value = req_headers.get(&name).cloned();
+            vary.push((name, value));
+        }
+        Some(vary)
+    }
+
+    async fn store(self: &Self, key: String, vary: Vec<(HeaderName, Option<HeaderValue>)>, ttl: Duration, res: HyperResp) -> hyper::Result<HyperResp> {
+        let (parts, body) = res.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        let now = Instant::now();
+        let stored = Stored {
+            vary,
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+            stored: now,
+            expires: now + ttl,
+        };
+        let mut store = self.store.lock().unwrap();
+        let mut variants = store.remove(&key).unwrap_or_default();
+        variants.retain(|v| v.expires > now && v.vary != stored.vary);
+        variants.push(stored);
+        let longest = variants.iter().map(|v| v.expires - now).max().unwrap_or(ttl);
+        store.insert(key, variants, longest);
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for ResponseCache {
+    async fn handle(self: &Self, req: HyperReq, next: Next<'_>) -> hyper::Result<HyperResp> {
+        let skipped = req.extensions().get::<MatchedRoute>().map(|r| self.skipped.contains(&r.0)).unwrap_or(false);
+        let directives = cache_control(req.headers());
+        if req.method() != Method::GET || skipped || has(&directives, "no-store") {
+            return next.run(req).await;
+        }
+        let key = req.uri().to_string();
+        let refresh = has(&directives, "no-cache") || seconds(&directives, "max-age") == Some(Duration::ZERO);
+        if !refresh {
+            if let Some(res) = self.lookup(&key, &req) {
+                return Ok(res);
+            }
+        }
+        // keep what the response may vary on before the request goes down the chain
+        let headers = req.headers().clone();
+        let res = next.run(req).await?;
+        match (self.ttl(&headers, &res), Self::vary(&headers, &res)) {
+            (Some(ttl), Some(vary)) => self.store(key, vary, ttl, res).await,
+            _ => Ok(res),
+        }
+    }
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn test_response_cache() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::time::sleep;
+    use crate::http::handler::{Filter, GET};
+    use crate::http::router::Router;
+
+    let calls = Arc::new(AtomicU32::new(0));
+    let counter = |cache_control: &'static str, vary: &'static str| {
+        let calls = calls.clone();
+        move |_| {
+            let n = calls.fetch_add(1, Ordering::SeqCst);
+            let mut res = Response::new(Body::from(n.to_string()));
+            res.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+            if !vary.is_empty() {
+                res.headers_mut().insert(header::VARY, HeaderValue::from_static(vary));
+            }
+            res
+        }
+    };
+    let mut r = Router::new();
+    r.add(GET().eq("/fresh").handle_request().then(counter("max-age=10", "")));
+    r.add(GET().eq("/private").handle_request().then(counter("private, max-age=10", "")));
+    r.add(GET().eq("/lang").handle_request().then(counter("max-age=10", "Accept-Language")));
+    r.add(GET().eq("/any").handle_request().then(counter("max-age=10", "*")));
+    r.add(GET().eq("/plain").handle_request().then(counter("", "")));
+    let session = counter("max-age=10", "");
+    r.add(GET().eq("/session").handle_request().then(move |req| {
+        let mut res = session(req);
+        res.headers_mut().insert(header::SET_COOKIE, HeaderValue::from_static("session=abc; HttpOnly"));
+        res
+    }));
+    r.wrap(ResponseCache::new(16));
+
+    let get = |path: &'static str, headers: &[(&'static str, &'static str)]| {
+        let mut req = Request::get(path).body(Body::empty()).unwrap();
+        for (name, value) in headers {
+            req.headers_mut().insert(*name, HeaderValue::from_static(value));
+        }
+        let r = &r;
+        async move {
+            let res = r.process(Method::GET, path.to_string(), req).await.unwrap();
+            let age = res.headers().get(header::AGE).map(|v| v.to_str().unwrap().to_string());
+            (String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap(), age)
+        }
+    };
+
+    assert_eq!(get("/fresh", &[]).await, ("0".to_string(), None));
+    sleep(Duration::from_secs(3)).await;
+    assert_eq!(get("/fresh", &[]).await, ("0".to_string(), Some("3".to_string())));
+    assert_eq!(get("/fresh", &[("cache-control", "no-cache")]).await.0, "1");
+    assert_eq!(get("/fresh", &[]).await.0, "1");
+    assert_eq!(get("/fresh", &[("cache-control", "no-store")]).await.0, "2");
+    sleep(Duration::from_secs(11)).await;
+    assert_eq!(get("/fresh", &[]).await.0, "3");
+
+    assert_eq!(get("/private", &[]).await.0, "4");
+    assert_eq!(get("/private", &[]).await.0, "5");
+    assert_eq!(get("/any", &[]).await.0, "6");
+    assert_eq!(get("/any", &[]).await.0, "7");
+    assert_eq!(get("/plain", &[]).await.0, "8");
+    assert_eq!(get("/plain", &[]).await.0, "9");
+
+    assert_eq!(get("/lang", &[("accept-language", "en")]).await.0, "10");
+    assert_eq!(get("/lang", &[("accept-language", "fr")]).await.0, "11");
+    assert_eq!(get("/lang", &[("accept-language", "en")]).await.0, "10");
+    assert_eq!(get("/lang", &[("accept-language", "fr")]).await.0, "11");
+    assert_eq!(get("/lang", &[]).await.0, "12");
+    assert_eq!(get("/session", &[]).await.0, "13");
+    assert_eq!(get("/session", &[]).await.0, "14");
+
+    let mut r = Router::new();
+    r.add(GET().eq("/plain").handle_request().then(counter("", "")));
+    r.wrap(ResponseCache::new(16).default_ttl(Duration::from_secs(5)));
+    let req = || Request::get("/plain").header(header::AUTHORIZATION, "Bearer x").body(Body::empty()).unwrap();
+    let body = |res: HyperResp| async { hyper::body::to_bytes(res.into_body()).await.unwrap() };
+    let first = body(r.process(Method::GET, "/plain".to_string(), req()).await.unwrap()).await;
+    let second = body(r.process(Method::GET, "/plain".to_string(), req()).await.unwrap()).await;
+    assert_ne!(first, second);
+    let req = || Request::get("/plain").body(Body::empty()).unwrap();
+    let first = body(r.process(Method::GET, "/plain".to_string(), req()).await.unwrap()).await;
+    let second = body(r.process(Method::GET, "/plain".to_string(), req()).await.unwrap()).await;
+    assert_eq!(first, second);
+}
@@ -0,0 +1,305 @@
+use std::io::{self, Read, Write};
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+
+use crate::http::accept::AcceptEncoding;
+use crate::http::middleware::{MatchedRoute, Middleware, Next};
+use crate::http::router::Router;
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self: &Self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    fn encode(self: &Self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                let mut w = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                w.write_all(data)?;
+                drop(w);
+                Ok(out)
+            }
+            Encoding::Zstd => zstd::encode_all(data, 3),
+            Encoding::Gzip => {
+                let mut w = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                w.write_all(data)?;
+                w.finish()
+            }
+            Encoding::Deflate => {
+                let mut w = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                w.write_all(data)?;
+                w.finish()
+            }
+        }
+    }
+
+    // Inflate at most `limit` bytes, `None` when the body is larger than that.
+    fn decode(self: &Self, data: &[u8], limit: usize) -> io::Result<Option<Vec<u8>>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Encoding::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+            Encoding::Zstd => Box::new(zstd::Decoder::new(data)?),
+            Encoding::Gzip => Box::new(flate2::read::GzDecoder::new(data)),
+            Encoding::Deflate => Box::new(flate2::read::ZlibDecoder::new(data)),
+        };
+        let mut out = Vec::new();
+        reader.take(limit as u64 + 1).read_to_end(&mut out)?;
+        Ok(if out.len() > limit { None } else { Some(out) })
+    }
+}
+
+// codecs are CPU bound on multi-megabyte bodies, run them off the runtime worker threads
+async fn blocking<T, F>(f: F) -> io::Result<T>
+    where F: FnOnce() -> io::Result<T> + Send + 'static,
+          T: Send + 'static {
+    tokio::task::spawn_blocking(f).await.map_err(io::Error::other)?
+}
+
+/**
+ * middleware compressing responses with the best encoding the client accepts,
+ * and inflating request bodies sent with a `Content-Encoding`.
+ * only bodies of known size are compressed, responses without content type are
+ * considered compressible, responses already carrying a `Content-Encoding`
+ * (including `identity`) are left untouched.
+ **/
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: usize,
+    content_types: Vec<String>,
+    skipped: Vec<String>,
+    max_inflated: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Compression {
+            encodings: vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip, Encoding::Deflate],
+            min_size: 1024,
+            content_types: ["text/", "application/json", "application/problem+json", "application/javascript",
+                "application/xml", "image/svg+xml"].iter().map(|s| s.to_string()).collect(),
+            skipped: Vec::new(),
+            max_inflated: 16 * 1024 * 1024,
+        }
+    }
+
+    // Encodings offered, in order of preference when the client weighs them equally.
+    pub fn encodings(mut self: Self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    pub fn min_size(mut self: Self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    // Content types, or prefixes of them like `text/`, that get compressed.
+    pub fn content_types(mut self: Self, types: &[&str]) -> Self {
+        self.content_types = types.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    // Never compress responses of the route with this pattern.
+    pub fn skip(mut self: Self, route: &str) -> Self {
+        self.skipped.push(route.to_string());
+        self
+    }
+
+    // Largest request body accepted once inflated, larger ones get a 413.
+    pub fn max_inflated(mut self: Self, bytes: usize) -> Self {
+        self.max_inflated = bytes;
+        self
+    }
+
+    /**
+     * pick the accepted encoding with the highest q-value,
+     * ties going to the first one in our preference list
+     **/
+    pub(crate) fn negotiate(self: &Self, accept: &str) -> Option<Encoding> {
+        let accept = AcceptEncoding::parse(accept);
+        self.encodings.iter()
+            .map(|e| (*e, accept.weight(e.name())))
+            .filter(|(_, q)| *q > 0.0)
+            .fold(None, |best: Option<(Encoding, f32)>, (e, q)| match best {
+                Some((_, bq)) if bq >= q => best,
+                _ => Some((e, q)),
+            })
+            .map(|(e, _)| e)
+    }
+
+    fn compressible(self: &Self, resp: &HyperResp) -> bool {
+        if resp.headers().contains_key(header::CONTENT_ENCODING)
+            || resp.status() == StatusCode::NO_CONTENT || resp.status() == StatusCode::NOT_MODIFIED
+            || resp.headers().contains_key(header::CONTENT_RANGE) {
+            return false;
+        }
+        let type_ok = match resp.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+            None => true,
+            Some(ct) => self.content_types.iter().any(|t| ct.starts_with(t.as_str())),
+        };
+        let size_ok = resp.body().size_hint().exact().map(|s| s as usize >= self.min_size).unwrap_or(false);
+        type_ok && size_ok
+    }
+
+    async fn inflate(self: &Self, mut req: HyperReq) -> Result<HyperReq, HyperResp> {
+        let encoding = match req.headers().get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok()) {
+            None => return Ok(req),
+            Some(v) if v.trim().eq_ignore_ascii_case("identity") => return Ok(req),
+            Some(v) => match Encoding::from_name(v) {
+                Some(e) => e,
+                None => return Err(Router::err_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported content encoding")),
+            }
+        };
+        let body = hyper::body::to_bytes(std::mem::take(req.body_mut())).await
+            .map_err(|e| Router::err_response(StatusCode::BAD_REQUEST, e.to_string()))?;
+        let limit = self.max_inflated;
+        let inflated = match blocking(move || encoding.decode(&body, limit)).await {
+            Ok(Some(inflated)) => inflated,
+            Ok(None) => return Err(Router::err_response(StatusCode::PAYLOAD_TOO_LARGE, "payload too large")),
+            Err(e) => return Err(Router::err_response(StatusCode::BAD_REQUEST, e.to_string())),
+        };
+        req.headers_mut().remove(header::CONTENT_ENCODING);
+        req.headers_mut().insert(header::CONTENT_LENGTH, HeaderValue::from(inflated.len()));
+        *req.body_mut() = Body::from(inflated);
+        Ok(req)
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Compression {
+    async fn handle(self: &Self, req: HyperReq, next: Next<'_>) -> hyper::Result<HyperResp> {
+        let req = match self.inflate(req).await {
+            Ok(req) => req,
+            Err(resp) => return Ok(resp),
+        };
+        let skipped = req.extensions().get::<MatchedRoute>().map(|r| self.skipped.contains(&r.0)).unwrap_or(false);
+        let accepted = req.headers().get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| self.negotiate(v));
+        let mut res = next.run(req).await?;
+        if skipped || !self.compressible(&res) {
+            return Ok(res);
+        }
+        res.headers_mut().append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+        let encoding = match accepted {
+            Some(e) => e,
+            None => return Ok(res),
+        };
+        let body: Bytes = hyper::body::to_bytes(std::mem::take(res.body_mut())).await?;
+        let data = body.clone();
+        match blocking(move || encoding.encode(&data)).await {
+            Ok(compressed) if compressed.len() < body.len() => {
+                let headers = res.headers_mut();
+                // another representation than the one the strong validator was computed for
+                if let Some(etag) = headers.get(header::ETAG).and_then(|v| v.to_str().ok()).filter(|v| !v.starts_with("W/")) {
+                    if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                        headers.insert(header::ETAG, weak);
+                    }
+                }
+                headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+                *res.body_mut() = Body::from(compressed);
+            }
+            _ => *res.body_mut() = Body::from(body),
+        }
+        Ok(res)
+    }
+}
+
+
+#[tokio::test]
+async fn test_compression() {
+    use hyper::Method;
+    use serde::{Deserialize, Serialize};
+    use crate::http::handler::{Filter, GET, POST};
+
+    #[derive(Serialize, Deserialize)]
+    struct Doc {
+        text: String,
+    }
+    let mut r = Router::new();
+    r.add(GET().eq("/big").handle_request().then(|_| Doc { text: "a".repeat(4096) }).to_json());
+    r.add(GET().eq("/raw").handle_request().then(|_| "b".repeat(4096)).ok());
+    r.add(GET().eq("/small").handle_request().then(|_| "small").ok());
+    r.add(GET().eq("/tagged").handle_request()
+        .then(|_| (StatusCode::OK, [("etag", "\"v1\"")], "t".repeat(4096)))
+        .respond());
+    r.add(POST().eq("/upload").handle_request().parse_json().then(|d: Doc| d.text.len().to_string()).ok());
+    r.wrap(Compression::new().skip("/raw"));
+
+    let get = |path: &str, accept: &str| Request::get(path).header(header::ACCEPT_ENCODING, accept).body(Body::empty()).unwrap();
+    for (accept, encoding) in [("gzip", Encoding::Gzip), ("gzip;q=0.5, br", Encoding::Brotli),
+        ("zstd, gzip", Encoding::Zstd), ("*", Encoding::Brotli)] {
+        let res = r.process(Method::GET, "/big".to_string(), get("/big", accept)).await.unwrap();
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], encoding.name());
+        assert_eq!(res.headers()[header::VARY], "Accept-Encoding");
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let plain = encoding.decode(&body, 1 << 20).unwrap().unwrap();
+        let doc: Doc = serde_json::from_slice(&plain).unwrap();
+        assert_eq!(doc.text.len(), 4096);
+    }
+
+    let res = r.process(Method::GET, "/big".to_string(), get("/big", "identity")).await.unwrap();
+    assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(res.headers()[header::VARY], "Accept-Encoding");
+    let res = r.process(Method::GET, "/big".to_string(), get("/big", "gzip;q=0")).await.unwrap();
+    assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    let res = r.process(Method::GET, "/small".to_string(), get("/small", "gzip")).await.unwrap();
+    assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    let res = r.process(Method::GET, "/tagged".to_string(), get("/tagged", "gzip")).await.unwrap();
+    assert_eq!(res.headers()[header::ETAG], "W/\"v1\"");
+    let res = r.process(Method::GET, "/tagged".to_string(), get("/tagged", "identity")).await.unwrap();
+    assert_eq!(res.headers()[header::ETAG], "\"v1\"");
+    let res = r.process(Method::GET, "/raw".to_string(), get("/raw", "gzip")).await.unwrap();
+    assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+
+    let payload = serde_json::to_vec(&Doc { text: "c".repeat(100) }).unwrap();
+    let req = Request::post("/upload")
+        .header(header::CONTENT_ENCODING, "gzip")
+        .body(Body::from(Encoding::Gzip.encode(&payload).unwrap())).unwrap();
+    let res = r.process(Method::POST, "/upload".to_string(), req).await.unwrap();
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "100");
+
+    let mut limited = Router::new();
+    limited.add(POST().eq("/upload").handle_request().parse_json().then(|d: Doc| d.text).ok());
+    limited.wrap(Compression::new().max_inflated(10));
+    let req = Request::post("/upload")
+        .header(header::CONTENT_ENCODING, "gzip")
+        .body(Body::from(Encoding::Gzip.encode(&payload).unwrap())).unwrap();
+    let res = limited.process(Method::POST, "/upload".to_string(), req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Request, Response};
+use log::warn;
+use ring::{aead, hkdf, hmac};
+
+use crate::http::handler::{EntryBase, Filter};
+use crate::http::static_files::percent_decode;
+use crate::pipeline::link::Pipeline;
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+const NONCE_LEN: usize = 12;
+
+// RFC 6265 cookie-octet, anything else is percent-encoded so a value can't end early or add attributes
+fn encode_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'%' => out.push_str("%25"),
+            0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E => out.push(b as char),
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+// RFC 7230 token
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/**
+ * cookie sent to the client as a `Set-Cookie` header, e.g.
+ * `Cookie::new("session", id).http_only(true).secure(true).same_site(SameSite::Lax)`.
+ * the value is percent-encoded where it is not a valid cookie octet, the
+ * `cookies` stage decodes it.
+ **/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    pub fn path(mut self: Self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn domain(mut self: Self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        self
+    }
+
+    // Without one the cookie lasts until the browser is closed.
+    pub fn max_age(mut self: Self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn secure(mut self: Self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self: Self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    // SameSite::None also sets Secure, browsers refuse it otherwise.
+    pub fn same_site(mut self: Self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self.secure |= same_site == SameSite::None;
+        self
+    }
+
+    pub fn name(self: &Self) -> &str {
+        &self.name
+    }
+
+    pub fn value(self: &Self) -> &str {
+        &self.value
+    }
+
+    // the name must be a token, the path and domain can't hold `;` or control characters
+    fn is_valid(self: &Self) -> bool {
+        let attribute = |s: &Option<String>| s.as_deref().is_none_or(|s| !s.bytes().any(|b| b == b';' || b.is_ascii_control()));
+        is_token(&self.name) && attribute(&self.path) && attribute(&self.domain)
+    }
+
+    fn is_removal(self: &Self) -> bool {
+        self.max_age == Some(Duration::ZERO)
+    }
+
+    // same cookie for the browser, which keeps one per name, path and domain
+    fn replaces(self: &Self, other: &Cookie) -> bool {
+        self.name == other.name && self.path == other.path && self.domain == other.domain
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, encode_value(&self.value))?;
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+            if self.is_removal() {
+                write!(f, "; Expires=Thu, 01 Jan 1970 00:00:00 GMT")?;
+            }
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Key {
+    signing: hmac::Key,
+    encryption: aead::LessSafeKey,
+}
+
+impl Key {
+    fn derive(secret: &[u8]) -> Self {
+        assert!(secret.len() >= 32, "cookie secret must be at least 32 bytes");
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, b"hyper-restful-rs cookies").extract(secret);
+        let signing = prk.expand(&[b"signing"], hmac::HMAC_SHA256).expect("hkdf output length");
+        let encryption = prk.expand(&[b"encryption"], &aead::AES_256_GCM).expect("hkdf output length");
+        Key {
+            signing: hmac::Key::from(signing),
+            encryption: aead::LessSafeKey::new(aead::UnboundKey::from(encryption)),
+        }
+    }
+}
+
+/**
+ * keys of the signed and private cookies, derived from server secrets of
+ * at least 32 bytes. cookies are written with the current secret and read
+ * with any of them, so a rotated secret stays in `previous` until the
+ * cookies it wrote have expired.
+ **/
+#[derive(Clone)]
+pub struct CookieKeys {
+    keys: Vec<Arc<Key>>,
+}
+
+impl CookieKeys {
+    pub fn new(secret: &[u8]) -> Self {
+        CookieKeys {
+            keys: vec![Arc::new(Key::derive(secret))],
+        }
+    }
+
+    pub fn previous(mut self: Self, secret: &[u8]) -> Self {
+        self.keys.push(Arc::new(Key::derive(secret)));
+        self
+    }
+
+    // `tag.value`, the tag covering the name so a value can't be moved to another cookie
+    fn sign(self: &Self, name: &str, value: &str) -> String {
+        let tag = hmac::sign(&self.keys[0].signing, format!("{}={}", name, value).as_bytes());
+        format!("{}.{}", URL_SAFE_NO_PAD.encode(tag), value)
+    }
+
+    fn verify(self: &Self, name: &str, signed: &str) -> Option<String> {
+        let (tag, value) = signed.split_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        let message = format!("{}={}", name, value);
+        self.keys.iter()
+            .any(|key| hmac::verify(&key.signing, message.as_bytes(), &tag).is_ok())
+            .then(|| value.to_string())
+    }
+
+    // base64 of the random nonce followed by the AES-256-GCM ciphertext, the name as associated data
+    fn encrypt(self: &Self, name: &str, value: &str) -> String {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut sealed = value.as_bytes().to_vec();
+        self.keys[0].encryption
+            .seal_in_place_append_tag(aead::Nonce::assume_unique_for_key(nonce), aead::Aad::from(name.as_bytes()), &mut sealed)
+            .expect("cookie value too long to encrypt");
+        URL_SAFE_NO_PAD.encode([&nonce[..], &sealed].concat())
+    }
+
+    fn decrypt(self: &Self, name: &str, encrypted: &str) -> Option<String> {
+        let data = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        self.keys.iter().find_map(|key| {
+            let nonce = aead::Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut sealed = sealed.to_vec();
+            let value = key.encryption.open_in_place(nonce, aead::Aad::from(name.as_bytes()), &mut sealed).ok()?;
+            String::from_utf8(value.to_vec()).ok()
+        })
+    }
+}
+
+#[derive(Default)]
+struct Jar {
+    keys: Option<CookieKeys>,
+    request: HashMap<String, String>,
+    changes: Vec<Cookie>,
+}
+
+/**
+ * cookies of the request being handled, put in the request extensions by
+ * the handler and filled by the `cookies` stage. the cookies added or
+ * removed by the stages are sent as `Set-Cookie` headers of the response,
+ * error responses included.
+ **/
+#[derive(Clone, Default)]
+pub struct Cookies(Arc<Mutex<Jar>>);
+
+pub fn cookies(req: &HyperReq) -> Option<Cookies> {
+    req.extensions().get::<Cookies>().cloned()
+}
+
+impl Cookies {
+    // Value sent by the client, or set by an earlier stage.
+    pub fn get(self: &Self, name: &str) -> Option<String> {
+        let jar = self.0.lock().unwrap();
+        match jar.changes.iter().rev().find(|c| c.name == name) {
+            Some(cookie) if cookie.is_removal() => None,
+            Some(cookie) => Some(cookie.value.clone()),
+            None => jar.request.get(name).cloned(),
+        }
+    }
+
+    pub fn add(self: &Self, cookie: Cookie) {
+        let mut jar = self.0.lock().unwrap();
+        jar.changes.retain(|c| !c.replaces(&cookie));
+        jar.changes.push(cookie);
+    }
+
+    // `cookie` only needs the name, path and domain it was set with.
+    pub fn remove(self: &Self, cookie: Cookie) {
+        self.add(Cookie {
+            value: String::new(),
+            max_age: Some(Duration::ZERO),
+            ..cookie
+        });
+    }
+
+    // None when it is missing or its signature is not valid for any key.
+    pub fn get_signed(self: &Self, name: &str) -> Option<String> {
+        let value = self.get(name)?;
+        self.keys()?.verify(name, &value)
+    }
+
+    // Readable by the client, but any change is detected.
+    pub fn add_signed(self: &Self, mut cookie: Cookie) {
+        match self.keys() {
+            Some(keys) => {
+                cookie.value = keys.sign(&cookie.name, &cookie.value);
+                self.add(cookie);
+            }
+            None => warn!("signed cookie {} dropped, the cookies stage has no keys", cookie.name),
+        }
+    }
+
+    pub fn get_private(self: &Self, name: &str) -> Option<String> {
+        let value = self.get(name)?;
+        self.keys()?.decrypt(name, &value)
+    }
+
+    // Encrypted and authenticated, the client can neither read nor change it.
+    pub fn add_private(self: &Self, mut cookie: Cookie) {
+        match self.keys() {
+            Some(keys) => {
+                cookie.value = keys.encrypt(&cookie.name, &cookie.value);
+                self.add(cookie);
+            }
+            None => warn!("private cookie {} dropped, the cookies stage has no keys", cookie.name),
+        }
+    }
+
+    // Cookies to send back, in the order they were added.
+    pub fn changes(self: &Self) -> Vec<Cookie> {
+        self.0.lock().unwrap().changes.clone()
+    }
+
+    fn keys(self: &Self) -> Option<CookieKeys> {
+        self.0.lock().unwrap().keys.clone()
+    }
+
+    // the first of the cookies with the same name wins, they are sent most specific path first
+    fn read(self: &Self, headers: &HeaderMap, keys: Option<CookieKeys>) {
+        let mut jar = self.0.lock().unwrap();
+        jar.keys = keys;
+        for value in headers.get_all(header::COOKIE) {
+            let Ok(value) = value.to_str() else { continue };
+            for pair in value.split(';') {
+                if let Some((name, value)) = pair.trim().split_once('=') {
+                    let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+                    let value = percent_decode(value).unwrap_or_else(|| value.to_string());
+                    jar.request.entry(name.to_string()).or_insert(value);
+                }
+            }
+        }
+    }
+
+    // cookies with an invalid name or attribute are skipped
+    fn write(self: &Self, headers: &mut HeaderMap) {
+        for cookie in self.0.lock().unwrap().changes.iter() {
+            if !cookie.is_valid() {
+                warn!("cookie {:?} dropped, invalid name, path or domain", cookie.name);
+                continue;
+            }
+            match HeaderValue::from_str(&cookie.to_string()) {
+                Ok(value) => {
+                    headers.append(header::SET_COOKIE, value);
+                }
+                Err(_) => warn!("cookie {} dropped, not a valid header value", cookie.name),
+            }
+        }
+    }
+}
+
+// runs `handle` with a jar in the request extensions, its changes become Set-Cookie headers of the response
+pub(crate) async fn with_jar<F, Fut>(mut req: HyperReq, handle: F) -> HyperResp
+    where F: FnOnce(HyperReq) -> Fut,
+          Fut: Future<Output=HyperResp> {
+    let jar = Cookies::default();
+    req.extensions_mut().insert(jar.clone());
+    let mut res = handle(req).await;
+    jar.write(res.headers_mut());
+    res
+}
+
+impl<T, P> EntryBase<T, P> where
+    T: Filter,
+    P: Pipeline<OUT=HyperReq> + Sync + Send {
+    // parses the Cookie header into the Cookies of the request
+    pub fn cookies(self: Self) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=HyperReq>> {
+        self.read_cookies(None)
+    }
+
+    // the same, with the keys of the signed and private cookies
+    pub fn cookies_with(self: Self, keys: CookieKeys) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=HyperReq>> {
+        self.read_cookies(Some(keys))
+    }
+
+    fn read_cookies(self: Self, keys: Option<CookieKeys>) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=HyperReq>> {
+        self.then_named("cookies", move |mut req: HyperReq| {
+            let jar = match cookies(&req) {
+                Some(jar) => jar,
+                None => {
+                    let jar = Cookies::default();
+                    req.extensions_mut().insert(jar.clone());
+                    jar
+                }
+            };
+            jar.read(req.headers(), keys.clone());
+            req
+        })
+    }
+}
+
+
+#[tokio::test]
+async fn test_cookies() {
+    use hyper::{Method, StatusCode};
+    use crate::http::error::HttpError;
+    use crate::http::handler::{GET, POST};
+    use crate::http::router::Router;
+
+    const OLD: &[u8; 32] = b"an old secret of thirty-two byte";
+    const NEW: &[u8; 32] = b"the new secret of 32 bytes long!";
+    let mut r = Router::new();
+    r.add(POST().eq("/login").handle_request().cookies_with(CookieKeys::new(OLD)).then(|req| {
+        let jar = cookies(&req).unwrap();
+        jar.add(Cookie::new("theme", "dark").path("/").max_age(Duration::from_secs(3600)));
+        jar.add_signed(Cookie::new("user", "alice").path("/").http_only(true));
+        jar.add_private(Cookie::new("session", "s3cr3t").path("/").http_only(true).same_site(SameSite::None));
+        "welcome"
+    }).ok());
+    r.add(GET().eq("/me").handle_request().cookies_with(CookieKeys::new(NEW).previous(OLD)).then(|req| {
+        let jar = cookies(&req).unwrap();
+        format!("{:?} {:?} {:?}", jar.get("theme"), jar.get_signed("user"), jar.get_private("session"))
+    }).ok());
+    r.add(POST().eq("/logout").handle_request().cookies().then_result(|req| -> Result<String, crate::pipeline::link::Error> {
+        let jar = cookies(&req).unwrap();
+        jar.remove(Cookie::new("session", "").path("/"));
+        assert_eq!(jar.get("session"), None);
+        Err(HttpError::unauthorized("logged out").into())
+    }).ok());
+
+    let req = Request::post("/login").body(Body::empty()).unwrap();
+    let res = r.process(Method::POST, "/login".to_string(), req).await.unwrap();
+    let set: Vec<&str> = res.headers().get_all(header::SET_COOKIE).iter().map(|v| v.to_str().unwrap()).collect();
+    assert_eq!(set.len(), 3);
+    assert_eq!(set[0], "theme=dark; Max-Age=3600; Path=/");
+    assert!(set[1].starts_with("user=") && set[1].ends_with(".alice; Path=/; HttpOnly"));
+    assert!(!set[2].contains("s3cr3t"));
+    assert!(set[2].ends_with("; Path=/; Secure; HttpOnly; SameSite=None"));
+
+    let sent: Vec<&str> = set.iter().map(|c| c.split(';').next().unwrap()).collect();
+    // a value can't add attributes, it is percent-encoded and read back as it was
+    let injected = Cookie::new("note", "x; Domain=evil.com; Path=/ 100%").path("/");
+    assert_eq!(injected.to_string(), "note=x%3B%20Domain=evil.com%3B%20Path=/%20100%25; Path=/");
+    let jar = Cookies::default();
+    let mut headers = HeaderMap::new();
+    headers.insert(header::COOKIE, HeaderValue::from_str(injected.to_string().split(';').next().unwrap()).unwrap());
+    jar.read(&headers, None);
+    assert_eq!(jar.get("note").as_deref(), Some("x; Domain=evil.com; Path=/ 100%"));
+    jar.add(Cookie::new("a;b", "v"));
+    jar.add(Cookie::new("c", "v").domain("example.com; Secure"));
+    jar.add(Cookie::new("ok", "v"));
+    let mut headers = HeaderMap::new();
+    jar.write(&mut headers);
+    assert_eq!(headers.get_all(header::SET_COOKIE).iter().collect::<Vec<_>>(), ["ok=v"]);
+
+    let me = |cookie: String| {
+        let req = Request::get("/me").header(header::COOKIE, cookie).body(Body::empty()).unwrap();
+        let r = &r;
+        async move {
+            let res = r.process(Method::GET, "/me".to_string(), req).await.unwrap();
+            String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap()
+        }
+    };
+    // written with the old secret, read after its rotation
+    assert_eq!(me(sent.join("; ")).await, r#"Some("dark") Some("alice") Some("s3cr3t")"#);
+    let tampered = sent[1].replace(".alice", ".admin");
+    let moved = format!("user={}", sent[2].trim_start_matches("session="));
+    assert_eq!(me(format!("{}; {}", tampered, moved)).await, "None None None");
+    let unknown = CookieKeys::new(&[7; 32]);
+    let forged = format!("user={}; session={}", unknown.sign("user", "alice"), unknown.encrypt("session", "x"));
+    assert_eq!(me(forged).await, "None None None");
+
+    let req = Request::post("/logout").header(header::COOKIE, "session=abc").body(Body::empty()).unwrap();
+    let res = r.process(Method::POST, "/logout".to_string(), req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(res.headers()[header::SET_COOKIE], "session=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Path=/");
+}
@@ -0,0 +1,320 @@
+use std::fmt;
+use std::time::Duration;
+
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue, InvalidHeaderName};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use regex::Regex;
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+// Invalid input to the Cors builder.
+#[derive(Debug)]
+pub enum CorsError {
+    InvalidHeaderName(InvalidHeaderName),
+    // any site could make credentialed reads if `*` was echoed as its origin
+    CredentialsWithAnyOrigin,
+}
+
+impl fmt::Display for CorsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorsError::InvalidHeaderName(e) => write!(f, "{}", e),
+            CorsError::CredentialsWithAnyOrigin => write!(f, "credentials can not be allowed for any origin"),
+        }
+    }
+}
+
+impl std::error::Error for CorsError {}
+
+impl From<InvalidHeaderName> for CorsError {
+    fn from(e: InvalidHeaderName) -> Self {
+        CorsError::InvalidHeaderName(e)
+    }
+}
+
+enum Origin {
+    Any,
+    Exact(String),
+    Pattern(Regex),
+}
+
+/**
+ * cross-origin policy of a Router. preflights for routes having a handler
+ * for the requested method are answered by the router itself, responses to
+ * actual cross-origin requests get the allow/expose headers added.
+ **/
+pub struct Cors {
+    origins: Vec<Origin>,
+    methods: Vec<Method>,
+    headers: Option<Vec<HeaderName>>,
+    expose: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cors {
+    // Nothing is allowed until origins are added.
+    pub fn new() -> Self {
+        Cors {
+            origins: Vec::new(),
+            methods: Vec::new(),
+            headers: Some(Vec::new()),
+            expose: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    // `*` allows every origin, a `*` inside the origin matches any sequence,
+    // e.g. `https://*.example.com`, otherwise the origin must be equal.
+    // Fails when `*` is combined with credentials.
+    pub fn allow_origin(mut self: Self, origin: &str) -> Result<Self, CorsError> {
+        let rule = if origin == "*" {
+            Origin::Any
+        } else if origin.contains('*') {
+            let pattern = origin.split('*').map(regex::escape).collect::<Vec<_>>().join(".*");
+            Origin::Pattern(Regex::new(&format!("^{}$", pattern)).unwrap())
+        } else {
+            Origin::Exact(origin.to_string())
+        };
+        self.origins.push(rule);
+        self.check_credentials()
+    }
+
+    pub fn allow_origin_regex(mut self: Self, regex: Regex) -> Self {
+        self.origins.push(Origin::Pattern(regex));
+        self
+    }
+
+    // Without any, the methods having a handler for the path are allowed.
+    pub fn allow_methods(mut self: Self, methods: &[Method]) -> Self {
+        self.methods.extend_from_slice(methods);
+        self
+    }
+
+    // Names in any case, e.g. `Content-Type`.
+    pub fn allow_headers(mut self: Self, headers: &[&str]) -> Result<Self, CorsError> {
+        let names = header_names(headers)?;
+        if let Some(list) = self.headers.as_mut() {
+            list.extend(names);
+        }
+        Ok(self)
+    }
+
+    // Allow whatever request headers the preflight asks for.
+    pub fn allow_any_header(mut self: Self) -> Self {
+        self.headers = None;
+        self
+    }
+
+    pub fn expose_headers(mut self: Self, headers: &[&str]) -> Result<Self, CorsError> {
+        self.expose.extend(header_names(headers)?);
+        Ok(self)
+    }
+
+    // Fails when every origin is allowed, list the trusted origins instead.
+    pub fn allow_credentials(mut self: Self, credentials: bool) -> Result<Self, CorsError> {
+        self.credentials = credentials;
+        self.check_credentials()
+    }
+
+    fn check_credentials(self: Self) -> Result<Self, CorsError> {
+        if self.credentials && self.any() {
+            return Err(CorsError::CredentialsWithAnyOrigin);
+        }
+        Ok(self)
+    }
+
+    fn any(self: &Self) -> bool {
+        self.origins.iter().any(|o| matches!(o, Origin::Any))
+    }
+
+    pub fn max_age(mut self: Self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allowed(self: &Self, origin: &str) -> bool {
+        self.origins.iter().any(|rule| match rule {
+            Origin::Any => true,
+            Origin::Exact(o) => o.eq_ignore_ascii_case(origin),
+            Origin::Pattern(re) => re.is_match(origin),
+        })
+    }
+
+    pub(crate) fn origin(req: &HyperReq) -> Option<HeaderValue> {
+        req.headers().get(header::ORIGIN).cloned()
+    }
+
+    pub(crate) fn is_preflight(req: &HyperReq) -> bool {
+        req.method() == Method::OPTIONS
+            && req.headers().contains_key(header::ORIGIN)
+            && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    pub(crate) fn requested_method(req: &HyperReq) -> Option<Method> {
+        req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|m| m.to_str().ok())
+            .and_then(|m| Method::from_bytes(m.as_bytes()).ok())
+    }
+
+    fn allow_origin_header(self: &Self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        if self.any() {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        }
+        if self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+    }
+
+    /**
+     * answer a preflight, `route_methods` are the methods having a handler for the path.
+     * a rejected preflight gets a 403 without any CORS header.
+     **/
+    pub(crate) fn preflight(self: &Self, req: &HyperReq, route_methods: &[Method]) -> HyperResp {
+        let reject = || Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from("cors preflight rejected"))
+            .unwrap();
+        let origin = match Self::origin(req) {
+            Some(o) if o.to_str().map(|o| self.allowed(o)).unwrap_or(false) => o,
+            _ => return reject(),
+        };
+        let methods: Vec<Method> = if self.methods.is_empty() {
+            route_methods.to_vec()
+        } else {
+            self.methods.iter().filter(|m| route_methods.contains(m)).cloned().collect()
+        };
+        match Self::requested_method(req) {
+            Some(m) if methods.contains(&m) => {}
+            _ => return reject(),
+        }
+        let requested: Vec<String> = req.headers().get_all(header::ACCESS_CONTROL_REQUEST_HEADERS).iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|h| h.trim().to_ascii_lowercase())
+            .filter(|h| !h.is_empty())
+            .collect();
+        if let Some(allowed) = &self.headers {
+            if !requested.iter().all(|h| allowed.iter().any(|a| a.as_str() == h)) {
+                return reject();
+            }
+        }
+
+        let mut res = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+        let headers = res.headers_mut();
+        self.allow_origin_header(headers, &origin);
+        let methods = methods.iter().map(|m| m.as_str()).collect::<Vec<_>>().join(", ");
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_str(&methods).unwrap());
+        if !requested.is_empty() {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS,
+                           HeaderValue::from_str(&requested.join(", ")).unwrap());
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+        }
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        headers.append(header::VARY, HeaderValue::from_static("Access-Control-Request-Method"));
+        headers.append(header::VARY, HeaderValue::from_static("Access-Control-Request-Headers"));
+        res
+    }
+
+    /**
+     * add the CORS headers of an actual request coming from `origin`. every
+     * response varies by Origin, so that caches don't give the one for a
+     * request without it or from another origin to an allowed origin.
+     **/
+    pub(crate) fn decorate(self: &Self, origin: Option<&HeaderValue>, res: &mut HyperResp) {
+        let headers = res.headers_mut();
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        let origin = match origin {
+            Some(o) if o.to_str().map(|o| self.allowed(o)).unwrap_or(false) => o,
+            _ => return,
+        };
+        self.allow_origin_header(headers, origin);
+        if !self.expose.is_empty() {
+            let expose = self.expose.iter().map(|h| h.as_str()).collect::<Vec<_>>().join(", ");
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_str(&expose).unwrap());
+        }
+    }
+}
+
+fn header_names(names: &[&str]) -> Result<Vec<HeaderName>, InvalidHeaderName> {
+    names.iter().map(|h| HeaderName::from_bytes(h.to_ascii_lowercase().as_bytes())).collect()
+}
+
+
+#[tokio::test]
+async fn test_cors() {
+    use crate::http::handler::{Filter, GET, OPTIONS, POST};
+    use crate::http::router::Router;
+    let mut r = Router::new();
+    r.add(GET().start_with("/items").handle_request().then(|_| "items").ok());
+    r.add(POST().start_with("/items").handle_request().then(|_| "created").ok());
+    r.add(OPTIONS().eq("/plain").handle_request().then(|_| "options").ok());
+    r.cors(Cors::new()
+        .allow_origin("https://*.example.com").unwrap()
+        .allow_origin_regex(Regex::new(r"^http://localhost:\d+$").unwrap())
+        .allow_headers(&["Content-Type"]).unwrap()
+        .expose_headers(&["X-Request-Id"]).unwrap()
+        .allow_credentials(true).unwrap()
+        .max_age(Duration::from_secs(600)));
+
+    let preflight = |origin: &str, method: &str, headers: &str| Request::options("/items/1")
+        .header(header::ORIGIN, origin)
+        .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+        .header(header::ACCESS_CONTROL_REQUEST_HEADERS, headers)
+        .body(Body::empty()).unwrap();
+
+    let res = r.process(Method::OPTIONS, "/items/1".to_string(),
+                        preflight("https://app.example.com", "POST", "Content-Type")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+    assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    assert_eq!(res.headers()[header::ACCESS_CONTROL_MAX_AGE], "600");
+    let methods = res.headers()[header::ACCESS_CONTROL_ALLOW_METHODS].to_str().unwrap();
+    assert!(methods.contains("GET") && methods.contains("POST"));
+
+    let res = r.process(Method::OPTIONS, "/items/1".to_string(),
+                        preflight("https://evil.com", "POST", "")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = r.process(Method::OPTIONS, "/items/1".to_string(),
+                        preflight("http://localhost:3000", "PUT", "")).await.unwrap();
+    // no PUT route, the preflight is dispatched like any OPTIONS request
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = r.process(Method::OPTIONS, "/items/1".to_string(),
+                        preflight("http://localhost:3000", "GET", "x-secret")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let req = Request::get("/items").header(header::ORIGIN, "http://localhost:8080").body(Body::empty()).unwrap();
+    let res = r.process(Method::GET, "/items".to_string(), req).await.unwrap();
+    assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "http://localhost:8080");
+    assert_eq!(res.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS], "x-request-id");
+    assert_eq!(res.headers()[header::VARY], "Origin");
+
+    let req = Request::get("/items").header(header::ORIGIN, "https://evil.com").body(Body::empty()).unwrap();
+    let res = r.process(Method::GET, "/items".to_string(), req).await.unwrap();
+    assert!(!res.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    assert_eq!(res.headers()[header::VARY], "Origin");
+    let res = r.process(Method::GET, "/items".to_string(), Request::new(Body::empty())).await.unwrap();
+    assert_eq!(res.headers()[header::VARY], "Origin");
+    assert!(Cors::new().allow_headers(&["bad header"]).is_err());
+    assert!(matches!(Cors::new().allow_origin("*").unwrap().allow_credentials(true), Err(CorsError::CredentialsWithAnyOrigin)));
+    assert!(matches!(Cors::new().allow_credentials(true).unwrap().allow_origin("*"), Err(CorsError::CredentialsWithAnyOrigin)));
+
+    // plain OPTIONS requests still reach their handler
+    let res = r.process(Method::OPTIONS, "/plain".to_string(), Request::new(Body::empty())).await.unwrap();
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "options");
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+
+use crate::http::problem::Problem;
+use crate::http::request_id::RequestId;
+use crate::http::validate::ValidationErrors;
+use crate::pipeline::link;
+use crate::pipeline::resilience::CircuitOpen;
+use crate::pipeline::stage::cause;
+
+type HyperResp = Response<Body>;
+
+/**
+ * error returned by a stage to answer with a given status instead of 500.
+ * the pipeline stops at the stage and the message becomes the body.
+ **/
+#[derive(Clone, Debug)]
+pub struct HttpError {
+    pub status: StatusCode,
+    pub message: String,
+    pub headers: HeaderMap,
+}
+
+impl HttpError {
+    pub fn new(status: StatusCode, message: &str) -> Self {
+        HttpError {
+            status,
+            message: message.to_string(),
+            headers: HeaderMap::new(),
+        }
+    }
+
+    pub fn unauthorized(message: &str) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, message)
+    }
+
+    pub fn forbidden(message: &str) -> Self {
+        Self::new(StatusCode::FORBIDDEN, message)
+    }
+
+    // Values that are not valid header values are ignored.
+    pub fn header(mut self: Self, name: HeaderName, value: &str) -> Self {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            self.headers.append(name, value);
+        }
+        self
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/**
+ * response for an error coming out of a pipeline: the status, headers and message
+ * of an HttpError or a Problem, 422 with the field errors as JSON for ValidationErrors,
+ * 504 for a timed out stage, 503 for an open circuit breaker, 500 otherwise with the
+ * message naming the failing stage. the response carries the matching Problem.
+ **/
+pub(crate) fn error_response(err: &link::Error, request_id: Option<&RequestId>) -> HyperResp {
+    let plain = |status: StatusCode, message: String| {
+        let message = match request_id {
+            Some(RequestId(id)) => format!("{} (request id: {})", message, id),
+            None => message,
+        };
+        let mut res = Response::new(Body::from(message));
+        *res.status_mut() = status;
+        res
+    };
+    let (mut res, problem) = if let Some(invalid) = cause::<ValidationErrors>(err) {
+        let problem = Problem::new(StatusCode::UNPROCESSABLE_ENTITY)
+            .detail("validation failed")
+            .extension("errors", &invalid.errors);
+        (validation_response(invalid, request_id), problem)
+    } else if let Some(problem) = cause::<Problem>(err) {
+        (plain(problem.status_code(), problem.to_string()), problem.clone())
+    } else if let Some(e) = cause::<HttpError>(err) {
+        let mut res = plain(e.status, e.message.clone());
+        res.headers_mut().extend(e.headers.clone());
+        (res, Problem::new(e.status).detail(&e.message))
+    } else {
+        let status = if cause::<tokio::time::error::Elapsed>(err).is_some() {
+            StatusCode::GATEWAY_TIMEOUT
+        } else if cause::<CircuitOpen>(err).is_some() {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        (plain(status, err.to_string()), Problem::new(status).detail(&err.to_string()))
+    };
+    res.extensions_mut().insert(problem);
+    res
+}
+
+fn validation_response(invalid: &ValidationErrors, request_id: Option<&RequestId>) -> HyperResp {
+    let mut body = serde_json::json!({
+        "message": "validation failed",
+        "errors": invalid.errors,
+    });
+    if let Some(RequestId(id)) = request_id {
+        body["request_id"] = serde_json::Value::from(id.as_str());
+    }
+    let mut res = Response::new(Body::from(body.to_string()));
+    *res.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+    res.headers_mut().insert(hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    res
+}
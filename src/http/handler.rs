@@ -1,4 +1,5 @@
 use std::future::Future;
+use std::time::Duration;
 
 use futures_util::future::ok;
 use futures_util::TryStreamExt;
@@ -6,7 +7,18 @@ use hyper::{Body, Method, Request, Response, StatusCode};
 use serde::{de, Serialize};
 
 
+use crate::http::authz::{denied, Guarded, Permission};
+#[cfg(feature = "cookies")]
+use crate::http::cookie::with_jar;
+use crate::http::error::{error_response, HttpError};
+use crate::http::request_id::RequestId;
+use crate::http::response::IntoResponse;
+use crate::http::validate::Validate;
+use crate::pipeline::boxed::BoxedPipeline;
+use crate::pipeline::branch::Switch;
 use crate::pipeline::connect::Connect;
+use crate::pipeline::parallel::{Forks, Racers};
+use crate::pipeline::resilience::CircuitBreaker;
 use crate::pipeline::link;
 use crate::pipeline::link::{begin, Linkable, Pipeline, Start};
 
@@ -16,6 +28,7 @@ macro_rules! generate_filter_method {
        pub fn $method() -> FilterBase<()> {
             FilterBase {
                 method: Method::$method,
+                pattern: String::from("*"),
                 inner: (),
             }
         }
@@ -24,10 +37,12 @@ macro_rules! generate_filter_method {
 
 type HyperResp = Response<Body>;
 type HyperReq = Request<Body>;
+type RequestPipeline = Connect<Start<(String, HyperReq)>, fn((String, HyperReq)) -> HyperReq>;
 
 generate_filter_method!(GET);
 generate_filter_method!(POST);
 generate_filter_method!(PUT);
+generate_filter_method!(OPTIONS);
 
 #[async_trait::async_trait]
 pub trait Handler: Filter + Send + Sync {
@@ -36,6 +51,33 @@ pub trait Handler: Filter + Send + Sync {
 
 pub trait Filter: Select + Send + Sync {
     fn test(self: &Self, path: &str) -> bool;
+    // route pattern used to label requests instead of the raw path
+    fn pattern(self: &Self) -> &str {
+        "*"
+    }
+    // permissions checked against the request Grants before the pipeline runs
+    fn permissions(self: &Self) -> Vec<Permission> {
+        Vec::new()
+    }
+    // the Grants come from the Authenticate middleware, the authentication
+    // stages run after the guards and can't be used on guarded routes.
+    fn require_scope(self: Self, scope: &str) -> Guarded<Self>
+        where Self: Sized,
+    {
+        Guarded {
+            inner: self,
+            required: Permission::Scope(scope.to_string()),
+        }
+    }
+    // same as require_scope, the Authenticate middleware is required.
+    fn require_role(self: Self, role: &str) -> Guarded<Self>
+        where Self: Sized,
+    {
+        Guarded {
+            inner: self,
+            required: Permission::Role(role.to_string()),
+        }
+    }
     fn handle(self: Self) -> EntryBase<Self, Start<(String, HyperReq)>>
         where Self: Sized,
     {
@@ -44,7 +86,7 @@ pub trait Filter: Select + Send + Sync {
             pipeline: begin(),
         }
     }
-    fn handle_request(self: Self) -> EntryBase<Self, Connect<Start<(String, HyperReq)>, fn((String, HyperReq)) -> HyperReq>>
+    fn handle_request(self: Self) -> EntryBase<Self, RequestPipeline>
         where Self: Sized,
     {
         EntryBase {
@@ -63,19 +105,29 @@ impl<T, P> Select for EntryBase<T, P> where P: Send + Sync, T: Filter {
     fn method(self: &Self) -> Method {
         self.test.method()
     }
+
+    fn methods(self: &Self) -> Vec<Method> {
+        self.test.methods()
+    }
 }
 
 impl<T, P> Filter for EntryBase<T, P> where T: Filter, P: Sync + Send {
     fn test(self: &Self, path: &str) -> bool {
         self.test.test(path)
     }
+    fn pattern(self: &Self) -> &str {
+        self.test.pattern()
+    }
+    fn permissions(self: &Self) -> Vec<Permission> {
+        self.test.permissions()
+    }
 }
 
 // we need return <impl Pipeline>, and know IN. so we have to define EntryBase<..,Start> specially
 impl<T, IN> EntryBase<T, Start<IN>> {
     pub fn then_async<NXT, F, Fut>(self: Self, f: F) -> EntryBase<T, impl Pipeline<IN=IN, OUT=NXT>>
         where F: Fn(IN) -> Fut + 'static + Send + Sync,
-              Fut: Future<Output=NXT> + Send + Sync + 'static,
+              Fut: Future<Output=NXT> + Send + 'static,
               IN: Send + Sync,
               NXT: Send + Sync,
               Self: Sized {
@@ -86,7 +138,7 @@ impl<T, IN> EntryBase<T, Start<IN>> {
     }
     pub fn then_async_result<NXT, F, Fut>(self: Self, f: F) -> EntryBase<T, impl Pipeline<IN=IN, OUT=NXT>>
         where F: Fn(IN) -> Fut + 'static + Send + Sync,
-              Fut: Future<Output=Result<NXT, link::Error>> + Send + Sync + 'static,
+              Fut: Future<Output=Result<NXT, link::Error>> + Send + 'static,
               IN: Send + Sync,
               NXT: Send + Sync,
               Self: Sized {
@@ -115,13 +167,55 @@ impl<T, IN> EntryBase<T, Start<IN>> {
             pipeline: self.pipeline.then_result(f),
         }
     }
+    pub fn then_named<NXT, F>(self: Self, name: &'static str, f: F) -> EntryBase<T, impl Pipeline<IN=IN, OUT=NXT>>
+        where F: Fn(IN) -> NXT + Send + Sync,
+              NXT: Send + Sync,
+              IN: Send + Sync,
+              Self: Sized {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.then_named(name, f),
+        }
+    }
+    pub fn then_async_named<NXT, F, Fut>(self: Self, name: &'static str, f: F) -> EntryBase<T, impl Pipeline<IN=IN, OUT=NXT>>
+        where F: Fn(IN) -> Fut + 'static + Send + Sync,
+              Fut: Future<Output=NXT> + Send + 'static,
+              IN: Send + Sync,
+              NXT: Send + Sync,
+              Self: Sized {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.then_async_named(name, f),
+        }
+    }
+    pub fn then_result_named<NXT, F>(self: Self, name: &'static str, f: F) -> EntryBase<T, impl Pipeline<IN=IN, OUT=NXT>>
+        where F: Fn(IN) -> Result<NXT, link::Error> + Send + Sync,
+              NXT: Send + Sync,
+              IN: Send + Sync,
+              Self: Sized {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.then_result_named(name, f),
+        }
+    }
+    pub fn then_async_result_named<NXT, F, Fut>(self: Self, name: &'static str, f: F) -> EntryBase<T, impl Pipeline<IN=IN, OUT=NXT>>
+        where F: Fn(IN) -> Fut + 'static + Send + Sync,
+              Fut: Future<Output=Result<NXT, link::Error>> + Send + 'static,
+              IN: Send + Sync,
+              NXT: Send + Sync,
+              Self: Sized {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.then_async_result_named(name, f),
+        }
+    }
 }
 
 impl<T, P> EntryBase<T, P>
     where P: Pipeline + Sync + Send {
     pub fn then_async<NXT, F, Fut>(self: Self, f: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=NXT>>
         where F: Fn(P::OUT) -> Fut + 'static + Send + Sync,
-              Fut: Future<Output=NXT> + Send + Sync + 'static,
+              Fut: Future<Output=NXT> + Send + 'static,
               NXT: Send + Sync,
               Self: Sized {
         EntryBase {
@@ -131,7 +225,7 @@ impl<T, P> EntryBase<T, P>
     }
     pub fn then_async_result<NXT, F, Fut>(self: Self, f: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=NXT>>
         where F: Fn(P::OUT) -> Fut + 'static + Send + Sync,
-              Fut: Future<Output=Result<NXT, link::Error>> + Send + Sync + 'static,
+              Fut: Future<Output=Result<NXT, link::Error>> + Send + 'static,
               NXT: Send + Sync,
               Self: Sized {
         EntryBase {
@@ -157,6 +251,143 @@ impl<T, P> EntryBase<T, P>
             pipeline: self.pipeline.then_result(f),
         }
     }
+    pub fn then_named<NXT, F>(self: Self, name: &'static str, f: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=NXT>>
+        where F: Fn(P::OUT) -> NXT + Send + Sync,
+              NXT: Send + Sync,
+              Self: Sized {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.then_named(name, f),
+        }
+    }
+    pub fn then_async_named<NXT, F, Fut>(self: Self, name: &'static str, f: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=NXT>>
+        where F: Fn(P::OUT) -> Fut + 'static + Send + Sync,
+              Fut: Future<Output=NXT> + Send + 'static,
+              NXT: Send + Sync,
+              Self: Sized {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.then_async_named(name, f),
+        }
+    }
+    pub fn then_result_named<NXT, F>(self: Self, name: &'static str, f: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=NXT>>
+        where F: Fn(P::OUT) -> Result<NXT, link::Error> + Send + Sync,
+              NXT: Send + Sync,
+              Self: Sized {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.then_result_named(name, f),
+        }
+    }
+    pub fn then_async_result_named<NXT, F, Fut>(self: Self, name: &'static str, f: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=NXT>>
+        where F: Fn(P::OUT) -> Fut + 'static + Send + Sync,
+              Fut: Future<Output=Result<NXT, link::Error>> + Send + 'static,
+              NXT: Send + Sync,
+              Self: Sized {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.then_async_result_named(name, f),
+        }
+    }
+    pub fn branch<C, A, B>(self: Self, predicate: C, then: A, otherwise: B) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=A::OUT>>
+        where C: Fn(&P::OUT) -> bool + Send + Sync,
+              A: Pipeline<IN=P::OUT> + Send + Sync,
+              B: Pipeline<IN=P::OUT, OUT=A::OUT> + Send + Sync {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.branch(predicate, then, otherwise),
+        }
+    }
+    // e.g. `.switch(|e| e.kind(), |s| s.case(Kind::A, a).otherwise(b))`
+    pub fn switch<KF, K, U, C>(self: Self, key: KF, cases: C) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=U>>
+        where KF: Fn(&P::OUT) -> K + Send + Sync,
+              K: PartialEq + Send + Sync,
+              U: Send + Sync,
+              C: FnOnce(Switch<P, KF, K, U>) -> Switch<P, KF, K, U> {
+        EntryBase {
+            test: self.test,
+            pipeline: cases(self.pipeline.switch(key)),
+        }
+    }
+    pub fn join<F>(self: Self, forks: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=F::OUT>>
+        where F: Forks<P::OUT> {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.join(forks),
+        }
+    }
+    pub fn race<R>(self: Self, racers: R) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=R::OUT>>
+        where R: Racers<P::OUT> {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.race(racers),
+        }
+    }
+    pub fn fan_out<S>(self: Self, pipeline: S, limit: usize) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=Vec<S::OUT>>>
+        where S: Pipeline + Send + Sync,
+              P::OUT: IntoIterator<Item=S::IN> {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.fan_out(pipeline, limit),
+        }
+    }
+    pub fn map_err<F>(self: Self, f: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=P::OUT>>
+        where F: Fn(link::Error) -> link::Error + Send + Sync {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.map_err(f),
+        }
+    }
+    pub fn recover<F>(self: Self, f: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=P::OUT>>
+        where F: Fn(link::Error) -> P::OUT + Send + Sync {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.recover(f),
+        }
+    }
+    pub fn or_else<F>(self: Self, f: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=P::OUT>>
+        where F: Fn(link::Error) -> Result<P::OUT, link::Error> + Send + Sync {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.or_else(f),
+        }
+    }
+    pub fn pipe<F>(self: Self, fragment: F) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=F::OUT>>
+        where F: Pipeline<IN=P::OUT> + Send + Sync {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.pipe(fragment),
+        }
+    }
+    // to keep the route in a field or return it from a function without `impl Trait`
+    pub fn boxed(self: Self) -> EntryBase<T, BoxedPipeline<P::IN, P::OUT>> where P: 'static {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.boxed(),
+        }
+    }
+    pub fn cached<KF, K>(self: Self, key: KF, ttl: Duration, capacity: usize) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=P::OUT>>
+        where P::OUT: Clone,
+              KF: Fn(&P::IN) -> K + Send + Sync,
+              K: Clone + Eq + std::hash::Hash + Send + Sync {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.cached(key, ttl, capacity),
+        }
+    }
+    // the request is not Clone, so retries go on fragments given to pipe, join, branch...
+    pub fn timeout(self: Self, duration: Duration) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=P::OUT>> {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.timeout(duration),
+        }
+    }
+    pub fn circuit_breaker(self: Self, breaker: CircuitBreaker) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=P::OUT>> {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.circuit_breaker(breaker),
+        }
+    }
 }
 
 
@@ -165,11 +396,21 @@ impl<T, P> Handler for EntryBase<T, P> where
     T: Filter,
     P: Pipeline<IN=(String, HyperReq), OUT=HyperResp> + Sync + Send, {
     async fn proc(self: &Self, path: String, body: HyperReq) -> hyper::Result<HyperResp> {
-        match self.pipeline.process((path, body)).await {
-            Ok(t) => Ok(t),
-            Err(err) => Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(err.to_string())).unwrap())
+        let request_id = body.extensions().get::<RequestId>().cloned();
+        if let Some(err) = denied(&self.test.permissions(), &body) {
+            return Ok(error_response(&err.into(), request_id.as_ref()));
         }
+        let run = |body: HyperReq| async move {
+            match self.pipeline.process((path, body)).await {
+                Ok(t) => t,
+                Err(err) => error_response(&err, request_id.as_ref()),
+            }
+        };
+        #[cfg(feature = "cookies")]
+        let res = with_jar(body, run).await;
+        #[cfg(not(feature = "cookies"))]
+        let res = run(body).await;
+        Ok(res)
     }
 }
 
@@ -185,14 +426,28 @@ impl<T, P> EntryBase<T, P> where
     {
         EntryBase {
             test: self.test,
-            pipeline: self.pipeline.then_async_result(|req| async {
+            pipeline: self.pipeline.then_async_result_named("parse_json", |req| async {
                 let mut body = Vec::new();
                 req.into_body()
                     .try_for_each(|bytes| {
                         body.extend(bytes);
                         ok(())
-                    }).await?;
-                Ok(serde_json::from_slice::<NXT>(&body)?)
+                    }).await
+                    .map_err(|e| HttpError::new(StatusCode::BAD_REQUEST, &format!("invalid body: {}", e)))?;
+                Ok(serde_json::from_slice::<NXT>(&body)
+                    .map_err(|e| HttpError::new(StatusCode::BAD_REQUEST, &format!("invalid JSON body: {}", e)))?)
+            }),
+        }
+    }
+
+    // rejects the value with a 422 listing the broken rules, e.g. right after parse_json
+    pub fn validate(self: Self) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=P::OUT>>
+        where P::OUT: Validate {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.then_result_named("validate", |value: P::OUT| match value.validate() {
+                Ok(()) => Ok(value),
+                Err(errors) => Err(errors.into()),
             }),
         }
     }
@@ -221,6 +476,15 @@ impl<T, P> EntryBase<T, P> where
         }
     }
 
+    // status, headers and body from the output, see IntoResponse
+    pub fn respond(self: Self) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=HyperResp>>
+        where P::OUT: IntoResponse {
+        EntryBase {
+            test: self.test,
+            pipeline: self.pipeline.then(IntoResponse::into_response),
+        }
+    }
+
     pub fn ret<IN>(self: Self) -> EntryBase<T, impl Pipeline<IN=P::IN, OUT=HyperResp>>
         where P: Pipeline<OUT=Result<IN, link::Error>>,
               IN: Into<Body>,
@@ -231,8 +495,7 @@ impl<T, P> EntryBase<T, P> where
                 match result {
                     Ok(msg) => Response::builder()
                         .status(StatusCode::OK).body(msg.into()).unwrap(),
-                    Err(err) => Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from(err.to_string())).unwrap()
+                    Err(err) => error_response(&err, None),
                 }
             }),
         }
@@ -245,7 +508,9 @@ impl<T, P> EntryBase<T, P> where
             test: self.test,
             pipeline: self.pipeline.then_async_result(|obj| async move {
                 let s = serde_json::to_string(&obj)?;
-                Ok(Response::new(Body::from(s)))
+                Ok(Response::builder()
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(s)).unwrap())
             }),
         }
     }
@@ -254,6 +519,7 @@ impl<T, P> EntryBase<T, P> where
 
 pub struct FilterBase<T> {
     method: Method,
+    pattern: String,
     inner: T,
 }
 
@@ -267,6 +533,9 @@ impl<T> Filter for FilterBase<T> where T: Fn(&str) -> bool + Sync + Send {
     fn test(self: &Self, path: &str) -> bool {
         (self.inner)(path)
     }
+    fn pattern(self: &Self) -> &str {
+        &self.pattern
+    }
 }
 
 impl<T> FilterBase<T> where T: Fn(&str) -> bool {}
@@ -275,6 +544,7 @@ impl FilterBase<()> {
     pub fn start_with(self: Self, prefix: &'static str) -> impl Filter {
         FilterBase {
             method: self.method,
+            pattern: format!("{}*", prefix),
             inner: move |path: &str| { path.starts_with(prefix) },
         }
     }
@@ -282,6 +552,7 @@ impl FilterBase<()> {
     pub fn eq(self: Self, prefix: &'static str) -> impl Filter {
         FilterBase {
             method: self.method,
+            pattern: prefix.to_string(),
             inner: move |path: &str| { path.eq(prefix) },
         }
     }
@@ -289,6 +560,11 @@ impl FilterBase<()> {
 
 pub trait Select {
     fn method(self: &Self) -> Method;
+
+    // Every method the handler is registered for.
+    fn methods(self: &Self) -> Vec<Method> {
+        vec![self.method()]
+    }
 }
 
 
@@ -390,4 +666,53 @@ async fn test_parse_json() {
     let res = h.proc("hello".to_string(), Request::new(Body::from(fjson))).await;
     let whole_body = hyper::body::to_bytes(res.unwrap().into_body()).await.unwrap();
     println!("ddd: {}", String::from_utf8(whole_body.to_vec()).unwrap());
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_branch_handler() {
+    use crate::pipeline::link::begin;
+    let h = GET().eq("/n").handle_request()
+        .then(|req| req.headers().contains_key("x-admin"))
+        .switch(|admin| *admin, |s| s
+            .case(true, begin::<bool>().then(|_| "admin"))
+            .otherwise(begin::<bool>().then_result(|_| Err("denied".into()))))
+        .recover(|_| "guest")
+        .ok()
+        .boxed();
+    let req = Request::get("/n").header("x-admin", "1").body(Body::empty()).unwrap();
+    let res = h.proc("/n".to_string(), req).await.unwrap();
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "admin");
+    let res = h.proc("/n".to_string(), Request::new(Body::empty())).await.unwrap();
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "guest");
+}
+
+
+#[tokio::test]
+async fn test_pipe_handler() {
+    use serde::Deserialize;
+    use crate::pipeline::link::begin;
+
+    #[derive(Deserialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+    // shared by both routes
+    let parse_point = || begin::<HyperReq>()
+        .then_async_result(|req| async move {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            Ok(serde_json::from_slice::<Point>(&body)?)
+        })
+        .then_result(|p| if p.x < 0 || p.y < 0 { Err("negative coordinate".into()) } else { Ok(p) });
+    let sum = POST().eq("/sum").handle_request().pipe(parse_point()).then(|p| (p.x + p.y).to_string()).ok();
+    let product = POST().eq("/product").handle_request().pipe(parse_point()).then(|p| (p.x * p.y).to_string()).ok();
+
+    let req = || Request::post("/").body(Body::from(r#"{"x": 3, "y": 4}"#)).unwrap();
+    let res = sum.proc("/sum".to_string(), req()).await.unwrap();
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "7");
+    let res = product.proc("/product".to_string(), req()).await.unwrap();
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "12");
+    let res = sum.proc("/sum".to_string(), Request::post("/").body(Body::from(r#"{"x": -1, "y": 4}"#)).unwrap()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "stage 1: negative coordinate");
+}
@@ -0,0 +1,191 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use hyper::{header, Body, Method, Response, StatusCode};
+use serde::Serialize;
+
+type HyperResp = Response<Body>;
+
+#[async_trait::async_trait]
+pub trait HealthCheck: Send + Sync {
+    async fn check(self: &Self) -> Result<(), String>;
+}
+
+#[async_trait::async_trait]
+impl<F, Fut> HealthCheck for F
+    where F: Fn() -> Fut + Send + Sync,
+          Fut: Future<Output=Result<(), String>> + Send + 'static {
+    async fn check(self: &Self) -> Result<(), String> {
+        (self)().await
+    }
+}
+
+struct Registered {
+    name: String,
+    timeout: Duration,
+    check: Box<dyn HealthCheck>,
+}
+
+#[derive(Serialize)]
+struct CheckReport {
+    name: String,
+    status: &'static str,
+    duration_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    status: &'static str,
+    draining: bool,
+    checks: Vec<CheckReport>,
+}
+
+/**
+ * built-in probe routes served by HttpServer before the router is consulted.
+ * readiness fails as soon as the server starts draining.
+ **/
+pub struct Health {
+    live_path: String,
+    ready_path: String,
+    drain_path: String,
+    checks: Vec<Registered>,
+    draining: Arc<AtomicBool>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            live_path: "/health/live".to_string(),
+            ready_path: "/health/ready".to_string(),
+            drain_path: "/health/drain".to_string(),
+            checks: Vec::new(),
+            draining: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn live_path(mut self: Self, path: &str) -> Self {
+        self.live_path = path.to_string();
+        self
+    }
+
+    pub fn ready_path(mut self: Self, path: &str) -> Self {
+        self.ready_path = path.to_string();
+        self
+    }
+
+    pub fn drain_path(mut self: Self, path: &str) -> Self {
+        self.drain_path = path.to_string();
+        self
+    }
+
+    /**
+     * register a dependency check run on every readiness probe,
+     * a check exceeding its timeout counts as failed
+     **/
+    pub fn check(mut self: Self, name: &str, timeout: Duration, check: impl HealthCheck + 'static) -> Self {
+        self.checks.push(Registered {
+            name: name.to_string(),
+            timeout,
+            check: Box::new(check),
+        });
+        self
+    }
+
+    pub(crate) fn share_draining(mut self: Self, draining: Arc<AtomicBool>) -> Self {
+        self.draining = draining;
+        self
+    }
+
+    pub fn is_draining(self: &Self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn matches(self: &Self, method: &Method, path: &str) -> bool {
+        *method == Method::GET
+            && (path == self.live_path || path == self.ready_path || path == self.drain_path)
+    }
+
+    pub(crate) async fn respond(self: &Self, path: &str) -> HyperResp {
+        let draining = self.is_draining();
+        if path == self.live_path {
+            Self::json_response(StatusCode::OK, &Report { status: "up", draining, checks: Vec::new() })
+        } else if path == self.drain_path {
+            let status = if draining { "draining" } else { "serving" };
+            Self::json_response(StatusCode::OK, &Report { status, draining, checks: Vec::new() })
+        } else if draining {
+            Self::json_response(StatusCode::SERVICE_UNAVAILABLE, &Report { status: "down", draining, checks: Vec::new() })
+        } else {
+            let checks = join_all(self.checks.iter().map(Self::run)).await;
+            let healthy = checks.iter().all(|c| c.error.is_none());
+            let (code, status) = if healthy {
+                (StatusCode::OK, "up")
+            } else {
+                (StatusCode::SERVICE_UNAVAILABLE, "down")
+            };
+            Self::json_response(code, &Report { status, draining, checks })
+        }
+    }
+
+    async fn run(registered: &Registered) -> CheckReport {
+        let begin = Instant::now();
+        let result = match tokio::time::timeout(registered.timeout, registered.check.check()).await {
+            Ok(r) => r,
+            Err(_) => Err(format!("timed out after {}ms", registered.timeout.as_millis())),
+        };
+        CheckReport {
+            name: registered.name.clone(),
+            status: if result.is_ok() { "up" } else { "down" },
+            duration_ms: begin.elapsed().as_millis(),
+            error: result.err(),
+        }
+    }
+
+    fn json_response(status: StatusCode, report: &Report) -> HyperResp {
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_string(report).unwrap()))
+            .unwrap()
+    }
+}
+
+
+#[tokio::test]
+async fn test_health() {
+    let h = Health::new()
+        .check("db", Duration::from_millis(50), || async { Ok(()) })
+        .check("slow", Duration::from_millis(10), || async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(())
+        });
+    assert!(h.matches(&Method::GET, "/health/ready"));
+    assert!(!h.matches(&Method::POST, "/health/ready"));
+
+    let live = h.respond("/health/live").await;
+    assert_eq!(live.status(), StatusCode::OK);
+
+    let ready = h.respond("/health/ready").await;
+    assert_eq!(ready.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = hyper::body::to_bytes(ready.into_body()).await.unwrap();
+    let v: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(v["checks"][0]["status"], "up");
+    assert_eq!(v["checks"][1]["status"], "down");
+
+    let draining = Arc::new(AtomicBool::new(false));
+    let h = Health::new().share_draining(draining.clone());
+    assert_eq!(h.respond("/health/ready").await.status(), StatusCode::OK);
+    draining.store(true, Ordering::SeqCst);
+    assert_eq!(h.respond("/health/ready").await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(h.respond("/health/live").await.status(), StatusCode::OK);
+}
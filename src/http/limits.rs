@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use log::warn;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+#[cfg(feature = "metrics")]
+use crate::http::metrics::Metrics;
+use crate::http::middleware::{MatchedRoute, Middleware, Next};
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+struct Limit {
+    permits: Arc<Semaphore>,
+    max_wait: Duration,
+}
+
+impl Limit {
+    fn new(max_in_flight: usize, max_wait: Duration) -> Self {
+        Limit {
+            permits: Arc::new(Semaphore::new(max_in_flight)),
+            max_wait,
+        }
+    }
+}
+
+/**
+ * middleware bounding the requests in flight, globally and per route
+ * pattern, and the time spent handling them. a request waits at most
+ * `max_wait` for a permit before being shed with 503, a request running
+ * past its timeout has its pipeline dropped and gets a 504.
+ **/
+pub struct Limits {
+    global: Option<Limit>,
+    routes: HashMap<String, Limit>,
+    timeout: Option<Duration>,
+    route_timeouts: HashMap<String, Duration>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Metrics>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Limits {
+            global: None,
+            routes: HashMap::new(),
+            timeout: None,
+            route_timeouts: HashMap::new(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    pub fn max_in_flight(mut self: Self, max: usize, max_wait: Duration) -> Self {
+        self.global = Some(Limit::new(max, max_wait));
+        self
+    }
+
+    pub fn route_max_in_flight(mut self: Self, pattern: &str, max: usize, max_wait: Duration) -> Self {
+        self.routes.insert(pattern.to_string(), Limit::new(max, max_wait));
+        self
+    }
+
+    pub fn timeout(mut self: Self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Overrides the global timeout for the route.
+    pub fn route_timeout(mut self: Self, pattern: &str, timeout: Duration) -> Self {
+        self.route_timeouts.insert(pattern.to_string(), timeout);
+        self
+    }
+
+    // Count shed requests and the waiting queue in `metrics`.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self: Self, metrics: &Metrics) -> Self {
+        self.metrics = Some(metrics.clone());
+        self
+    }
+
+    fn shed(self: &Self, route: &str, reason: &str) {
+        warn!("request to {} refused: {}", route, reason);
+        #[cfg(feature = "metrics")]
+        if let Some(m) = &self.metrics {
+            m.observe_shed(route, reason);
+        }
+    }
+
+    async fn acquire(self: &Self, limit: &Limit) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = limit.permits.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        #[cfg(feature = "metrics")]
+        let _queued = self.metrics.as_ref().map(Queued::enter);
+        tokio::time::timeout(limit.max_wait, limit.permits.clone().acquire_owned()).await
+            .ok()
+            .and_then(|p| p.ok())
+    }
+}
+
+#[cfg(feature = "metrics")]
+struct Queued<'a>(&'a prometheus::IntGauge);
+
+#[cfg(feature = "metrics")]
+impl<'a> Queued<'a> {
+    fn enter(metrics: &'a Metrics) -> Self {
+        metrics.queued().inc();
+        Queued(metrics.queued())
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl<'a> Drop for Queued<'a> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+fn refuse(status: StatusCode, message: &'static str) -> HyperResp {
+    let mut res = Response::builder().status(status).body(Body::from(message)).unwrap();
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+    }
+    res
+}
+
+#[async_trait::async_trait]
+impl Middleware for Limits {
+    async fn handle(self: &Self, req: HyperReq, next: Next<'_>) -> hyper::Result<HyperResp> {
+        let route = req.extensions().get::<MatchedRoute>()
+            .map(|r| r.0.clone())
+            .unwrap_or_else(|| "unmatched".to_string());
+        let mut _permits = Vec::new();
+        for (limit, reason) in [(self.global.as_ref(), "global"), (self.routes.get(&route), "route")] {
+            if let Some(limit) = limit {
+                match self.acquire(limit).await {
+                    Some(permit) => _permits.push(permit),
+                    None => {
+                        self.shed(&route, reason);
+                        return Ok(refuse(StatusCode::SERVICE_UNAVAILABLE, "overloaded, retry later"));
+                    }
+                }
+            }
+        }
+        match self.route_timeouts.get(&route).copied().or(self.timeout) {
+            None => next.run(req).await,
+            Some(timeout) => match tokio::time::timeout(timeout, next.run(req)).await {
+                Ok(res) => res,
+                Err(_) => {
+                    self.shed(&route, "timeout");
+                    Ok(refuse(StatusCode::GATEWAY_TIMEOUT, "request timed out"))
+                }
+            },
+        }
+    }
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn test_limits() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use hyper::Method;
+    use crate::http::handler::{Filter, GET};
+    use crate::http::router::Router;
+    let finished = Arc::new(AtomicBool::new(false));
+    let done = finished.clone();
+    let mut r = Router::new();
+    r.add(GET().eq("/slow").handle_request()
+        .then_async(|_| async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            "slow"
+        })
+        .ok());
+    r.add(GET().eq("/stuck").handle_request()
+        .then_async(move |_| {
+            let done = done.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                done.store(true, Ordering::SeqCst);
+                "stuck"
+            }
+        })
+        .ok());
+    r.add(GET().eq("/fast").handle_request().then(|_| "fast").ok());
+    r.wrap(Limits::new()
+        .max_in_flight(10, Duration::from_secs(1))
+        .route_max_in_flight("/slow", 1, Duration::from_millis(500))
+        .timeout(Duration::from_secs(5))
+        .route_timeout("/stuck", Duration::from_secs(1)));
+
+    let call = |path: &'static str| r.process(Method::GET, path.to_string(), Request::new(Body::empty()));
+
+    // the second slow request waits 500ms for the only permit then is shed
+    let (a, b, c) = tokio::join!(call("/slow"), call("/slow"), call("/fast"));
+    assert_eq!(a.unwrap().status(), StatusCode::OK);
+    let b = b.unwrap();
+    assert_eq!(b.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(b.headers()[header::RETRY_AFTER], "1");
+    assert_eq!(c.unwrap().status(), StatusCode::OK);
+
+    let res = call("/stuck").await.unwrap();
+    assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+    tokio::time::sleep(Duration::from_secs(120)).await;
+    assert!(!finished.load(Ordering::SeqCst));
+}
@@ -0,0 +1,234 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hyper::body::HttpBody;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use prometheus::core::Collector;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+use crate::http::middleware::{MatchedRoute, Middleware, Next};
+use crate::http::panic::Panicked;
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+struct Collectors {
+    registry: Registry,
+    requests: IntCounterVec,
+    in_flight: IntGauge,
+    latency: HistogramVec,
+    response_size: HistogramVec,
+    stages: HistogramVec,
+    shed: IntCounterVec,
+    queued: IntGauge,
+    panics: IntCounterVec,
+}
+
+/**
+ * prometheus collectors for the requests going through a Router,
+ * labelled by method, matched route pattern and status.
+ * install it with `Router::wrap`, it also serves the text exposition on its path.
+ **/
+#[derive(Clone)]
+pub struct Metrics {
+    path: String,
+    collectors: Arc<Collectors>,
+}
+
+struct InFlight<'a>(&'a IntGauge);
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::with_registry(Registry::new()).expect("collectors registered in a new registry")
+    }
+
+    /**
+     * register the collectors in an application registry. fails when it
+     * already holds collectors of the same names, e.g. registered by another
+     * Metrics, in which case none of them is left registered.
+     **/
+    pub fn with_registry(registry: Registry) -> Result<Self, prometheus::Error> {
+        let labels = &["method", "route", "status"];
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "number of handled requests"), labels).unwrap();
+        let in_flight = IntGauge::new(
+            "http_requests_in_flight", "number of requests being handled").unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "request latency"), labels).unwrap();
+        let response_size = HistogramVec::new(
+            HistogramOpts::new("http_response_size_bytes", "response body size")
+                .buckets(prometheus::exponential_buckets(64.0, 4.0, 10).unwrap()), labels).unwrap();
+        let stages = HistogramVec::new(
+            HistogramOpts::new("pipeline_stage_duration_seconds", "pipeline stage latency"), &["stage"]).unwrap();
+        let shed = IntCounterVec::new(
+            Opts::new("http_requests_shed_total", "requests refused by the limits"), &["route", "reason"]).unwrap();
+        let queued = IntGauge::new(
+            "http_requests_queued", "requests waiting for a concurrency permit").unwrap();
+        let panics = IntCounterVec::new(
+            Opts::new("http_handler_panics_total", "handlers that panicked"), &["route"]).unwrap();
+        let all = || -> Vec<Box<dyn Collector>> {
+            vec![Box::new(requests.clone()), Box::new(in_flight.clone()), Box::new(latency.clone()),
+                 Box::new(response_size.clone()), Box::new(stages.clone()), Box::new(shed.clone()),
+                 Box::new(queued.clone()), Box::new(panics.clone())]
+        };
+        for (i, collector) in all().into_iter().enumerate() {
+            if let Err(e) = registry.register(collector) {
+                for registered in all().into_iter().take(i) {
+                    let _ = registry.unregister(registered);
+                }
+                return Err(e);
+            }
+        }
+        Ok(Metrics {
+            path: "/metrics".to_string(),
+            collectors: Arc::new(Collectors {
+                registry,
+                requests,
+                in_flight,
+                latency,
+                response_size,
+                stages,
+                shed,
+                queued,
+                panics,
+            }),
+        })
+    }
+
+    pub fn path(mut self: Self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    // Register user-defined collectors here so that they are exposed along with ours.
+    pub fn registry(self: &Self) -> &Registry {
+        &self.collectors.registry
+    }
+
+    pub fn observe_stage(self: &Self, stage: &str, elapsed: Duration) {
+        self.collectors.stages.with_label_values(&[stage]).observe(elapsed.as_secs_f64());
+    }
+
+    /**
+     * time a pipeline stage, e.g.
+     * `.then_async(move |x| m.time_stage("load_user", load_user(x)))`
+     **/
+    pub async fn time_stage<F: Future>(self: &Self, stage: &str, fut: F) -> F::Output {
+        let begin = Instant::now();
+        let out = fut.await;
+        self.observe_stage(stage, begin.elapsed());
+        out
+    }
+
+    // `reason` is the limit that refused the request, e.g. global, route or timeout.
+    pub(crate) fn observe_shed(self: &Self, route: &str, reason: &str) {
+        self.collectors.shed.with_label_values(&[route, reason]).inc();
+    }
+
+    pub(crate) fn queued(self: &Self) -> &IntGauge {
+        &self.collectors.queued
+    }
+
+    pub fn render(self: &Self) -> HyperResp {
+        let encoder = TextEncoder::new();
+        let mut buffer = Vec::new();
+        encoder.encode(&self.collectors.registry.gather(), &mut buffer).unwrap();
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buffer))
+            .unwrap()
+    }
+
+    fn response_size(resp: &HyperResp) -> Option<u64> {
+        resp.body().size_hint().exact().or_else(|| {
+            resp.headers().get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Metrics {
+    async fn handle(self: &Self, req: HyperReq, next: Next<'_>) -> hyper::Result<HyperResp> {
+        if req.method() == Method::GET && req.uri().path() == self.path {
+            return Ok(self.render());
+        }
+        let method = req.method().to_string();
+        let route = req.extensions().get::<MatchedRoute>()
+            .map(|r| r.0.clone())
+            .unwrap_or_else(|| "unmatched".to_string());
+        let c = &self.collectors;
+        c.in_flight.inc();
+        let _guard = InFlight(&c.in_flight);
+        let begin = Instant::now();
+        let res = next.run(req).await;
+        if let Ok(resp) = &res {
+            let status = resp.status().as_u16().to_string();
+            let labels = [method.as_str(), route.as_str(), status.as_str()];
+            c.requests.with_label_values(&labels).inc();
+            c.latency.with_label_values(&labels).observe(begin.elapsed().as_secs_f64());
+            if let Some(size) = Self::response_size(resp) {
+                c.response_size.with_label_values(&labels).observe(size as f64);
+            }
+            if resp.extensions().get::<Panicked>().is_some() {
+                c.panics.with_label_values(&[route.as_str()]).inc();
+            }
+        }
+        res
+    }
+}
+
+
+#[tokio::test]
+async fn test_metrics() {
+    use crate::http::handler::{Filter, GET};
+    use crate::http::router::Router;
+    let metrics = Metrics::new();
+    let mut r = Router::new();
+    let m = metrics.clone();
+    r.add(GET().start_with("/user").handle_request()
+        .then_async(move |_| {
+            let m = m.clone();
+            async move { m.time_stage("load_user", async { "bob" }).await }
+        })
+        .ok());
+    r.wrap(metrics.clone());
+    r.process(Method::GET, "/user/1".to_string(), Request::new(Body::empty())).await.unwrap();
+    r.process(Method::GET, "/user/2".to_string(), Request::new(Body::empty())).await.unwrap();
+    r.process(Method::GET, "/nope".to_string(), Request::new(Body::empty())).await.unwrap();
+    r.add(GET().eq("/panic").handle_request().then(|_| -> &'static str { panic!("boom") }).ok());
+    r.process(Method::GET, "/panic".to_string(), Request::new(Body::empty())).await.unwrap();
+
+    let res = r.process(Method::GET, "/metrics".to_string(),
+                        Request::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+    let text = String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap();
+    assert!(text.contains(r#"http_requests_total{method="GET",route="/user*",status="200"} 2"#));
+    assert!(text.contains(r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#));
+    assert!(text.contains(r#"pipeline_stage_duration_seconds_count{stage="load_user"} 2"#));
+    assert!(text.contains("http_requests_in_flight 0"));
+    assert!(text.contains(r#"http_handler_panics_total{route="/panic"} 1"#));
+
+    // a registry shared by two routers takes the collectors once
+    let registry = Registry::new();
+    assert!(Metrics::with_registry(registry.clone()).is_ok());
+    assert!(Metrics::with_registry(registry.clone()).is_err());
+    let other = Registry::new();
+    other.register(Box::new(IntGauge::new("http_requests_queued", "taken").unwrap())).unwrap();
+    assert!(Metrics::with_registry(other.clone()).is_err());
+    assert_eq!(other.gather().len(), 1);
+}
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use hyper::{Body, Request, Response, StatusCode};
+
+use crate::http::handler::Handler;
+use crate::http::panic::{CatchPanic, OnPanic};
+use crate::http::request_id::RequestId;
+use crate::http::router::Router;
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+/**
+ * wraps the dispatch of every request going through a Router,
+ * including the ones no handler matched.
+ * call `next.run(req)` to continue, or return early to short-circuit.
+ **/
+#[async_trait::async_trait]
+pub trait Middleware: Send + Sync {
+    async fn handle(self: &Self, req: HyperReq, next: Next<'_>) -> hyper::Result<HyperResp>;
+}
+
+/**
+ * route pattern of the handler selected for the request, stored in the
+ * request extensions before any middleware runs. absent when nothing matched.
+ **/
+#[derive(Clone, Debug)]
+pub struct MatchedRoute(pub String);
+
+pub(crate) enum Endpoint<'a> {
+    Handler(&'a dyn Handler, String, OnPanic),
+    Reject(StatusCode, &'static str),
+    Ready(HyperResp),
+}
+
+pub struct Next<'a> {
+    pub(crate) middlewares: &'a [Arc<dyn Middleware>],
+    pub(crate) endpoint: Endpoint<'a>,
+}
+
+impl<'a> Next<'a> {
+    pub async fn run(self: Self, req: HyperReq) -> hyper::Result<HyperResp> {
+        match self.middlewares.split_first() {
+            Some((first, rest)) => {
+                first.handle(req, Next {
+                    middlewares: rest,
+                    endpoint: self.endpoint,
+                }).await
+            }
+            None => match self.endpoint {
+                Endpoint::Handler(handler, path, on_panic) => {
+                    let request_id = req.extensions().get::<RequestId>().cloned();
+                    match CatchPanic::new(handler.proc(path, req)).await {
+                        Ok(Ok(t)) => Ok(t),
+                        Ok(Err(e)) => Ok(Router::err_response(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            e.to_string(),
+                        )),
+                        Err(panicked) => {
+                            if on_panic == OnPanic::Abort {
+                                // the logger may still hold the panic message and backtrace in its queue
+                                log::logger().flush();
+                                std::process::abort();
+                            }
+                            let message = match request_id {
+                                Some(RequestId(id)) => format!("internal server error (request id: {})", id),
+                                None => "internal server error".to_string(),
+                            };
+                            let mut res = Router::err_response(StatusCode::INTERNAL_SERVER_ERROR, message);
+                            res.extensions_mut().insert(panicked);
+                            Ok(res)
+                        }
+                    }
+                }
+                Endpoint::Reject(status, message) => Ok(Router::err_response(status, message)),
+                Endpoint::Ready(resp) => Ok(resp),
+            }
+        }
+    }
+}
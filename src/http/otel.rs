@@ -0,0 +1,381 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::{header, Body, Client, Method, Request};
+use log::warn;
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::http::trace::{hex, unhex};
+
+struct SpanData {
+    name: &'static str,
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: Vec<(String, String)>,
+}
+
+enum Message {
+    Span(SpanData, SystemTime),
+    Flush(SyncSender<()>),
+}
+
+struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+impl<'a> Fields<'a> {
+    fn set(self: &mut Self, field: &Field, value: String) {
+        match self.0.iter_mut().find(|(k, _)| k == field.name()) {
+            Some(entry) => entry.1 = value,
+            None => self.0.push((field.name().to_string(), value)),
+        }
+    }
+}
+
+impl<'a> Visit for Fields<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.set(field, format!("{:?}", value));
+    }
+}
+
+fn attribute_id<const N: usize>(attributes: &[(String, String)], key: &str) -> Option<[u8; N]> {
+    unhex(&attributes.iter().find(|(k, _)| k == key)?.1)
+}
+
+/**
+ * tracing layer exporting closed spans to an OTLP/HTTP collector as JSON.
+ * request spans opened by HttpServer carry the W3C ids of the request,
+ * other spans inherit the trace of their parent or start a new one.
+ * spans are dropped when the export queue is full.
+ **/
+pub struct OtlpLayer {
+    tx: SyncSender<Message>,
+    dropped: Arc<AtomicU64>,
+}
+
+// Handle to the exporter thread, dropping it does not stop the export.
+#[derive(Clone)]
+pub struct OtlpHandle {
+    tx: SyncSender<Message>,
+    dropped: Arc<AtomicU64>,
+    timeout: Duration,
+}
+
+impl OtlpHandle {
+    // Number of spans lost because the exporter could not keep up.
+    pub fn dropped(self: &Self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /**
+     * block until every span closed so far has been sent to the collector.
+     * waits at most twice the export timeout, a post in progress and the
+     * flushing one, so a stuck collector can't hang an exit.
+     **/
+    pub fn flush(self: &Self) {
+        let deadline = Instant::now() + self.timeout * 2;
+        let (ack, done) = sync_channel(1);
+        let mut msg = Message::Flush(ack);
+        loop {
+            match self.tx.try_send(msg) {
+                Ok(()) => break,
+                Err(TrySendError::Full(m)) if Instant::now() < deadline => {
+                    msg = m;
+                    thread::sleep(Duration::from_millis(5));
+                }
+                Err(_) => return,
+            }
+        }
+        let _ = done.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+pub struct OtlpExporter {
+    endpoint: String,
+    service_name: String,
+    batch_size: usize,
+    interval: Duration,
+    capacity: usize,
+    timeout: Duration,
+}
+
+impl OtlpExporter {
+    // `endpoint` is the full traces url, e.g. `http://127.0.0.1:4318/v1/traces`.
+    pub fn new(endpoint: &str) -> Self {
+        OtlpExporter {
+            endpoint: endpoint.to_string(),
+            service_name: "unknown_service".to_string(),
+            batch_size: 512,
+            interval: Duration::from_secs(5),
+            capacity: 2048,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    pub fn service_name(mut self: Self, name: &str) -> Self {
+        self.service_name = name.to_string();
+        self
+    }
+
+    pub fn batch_size(mut self: Self, spans: usize) -> Self {
+        self.batch_size = spans;
+        self
+    }
+
+    pub fn interval(mut self: Self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    // Spans queued for the exporter before new ones are dropped.
+    pub fn capacity(mut self: Self, spans: usize) -> Self {
+        self.capacity = spans;
+        self
+    }
+
+    // Deadline of each post to the collector.
+    pub fn timeout(mut self: Self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn build(self: Self) -> std::io::Result<(OtlpLayer, OtlpHandle)> {
+        let (tx, rx) = sync_channel::<Message>(self.capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let timeout = self.timeout;
+        thread::Builder::new().name("otlp-exporter".to_string()).spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            let client = Client::new();
+            let mut batch = Vec::new();
+            let mut deadline = Instant::now() + self.interval;
+            loop {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                let (closed, ack) = match rx.recv_timeout(wait) {
+                    Ok(Message::Span(span, end)) => {
+                        if batch.is_empty() {
+                            deadline = Instant::now() + self.interval;
+                        }
+                        batch.push(self.encode(span, end));
+                        if batch.len() < self.batch_size {
+                            continue;
+                        }
+                        (false, None)
+                    }
+                    Ok(Message::Flush(ack)) => (false, Some(ack)),
+                    Err(RecvTimeoutError::Timeout) => (false, None),
+                    Err(RecvTimeoutError::Disconnected) => (true, None),
+                };
+                if !batch.is_empty() {
+                    let body = self.envelope(std::mem::take(&mut batch));
+                    runtime.block_on(self.post(&client, body));
+                }
+                deadline = Instant::now() + self.interval;
+                if let Some(ack) = ack {
+                    let _ = ack.send(());
+                }
+                if closed {
+                    break;
+                }
+            }
+        })?;
+        Ok((OtlpLayer { tx: tx.clone(), dropped: dropped.clone() }, OtlpHandle { tx, dropped, timeout }))
+    }
+
+    fn encode(self: &Self, span: SpanData, end: SystemTime) -> Value {
+        let nanos = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+        let attributes: Vec<Value> = span.attributes.iter()
+            .filter(|(k, _)| !matches!(k.as_str(), "trace_id" | "span_id" | "parent_id"))
+            .map(|(k, v)| json!({"key": k, "value": {"stringValue": v}}))
+            .collect();
+        let mut encoded = json!({
+            "traceId": hex(&span.trace_id),
+            "spanId": hex(&span.span_id),
+            "name": span.name,
+            // SPAN_KIND_SERVER for request spans, SPAN_KIND_INTERNAL otherwise
+            "kind": if span.name == "request" { 2 } else { 1 },
+            "startTimeUnixNano": nanos(span.start),
+            "endTimeUnixNano": nanos(end),
+            "attributes": attributes,
+        });
+        if let Some(parent) = span.parent_id {
+            encoded["parentSpanId"] = json!(hex(&parent));
+        }
+        encoded
+    }
+
+    fn envelope(self: &Self, spans: Vec<Value>) -> String {
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{"key": "service.name", "value": {"stringValue": self.service_name}}]
+                },
+                "scopeSpans": [{
+                    "scope": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+                    "spans": spans,
+                }]
+            }]
+        }).to_string()
+    }
+
+    async fn post(self: &Self, client: &Client<hyper::client::HttpConnector>, body: String) {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(&self.endpoint)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        match tokio::time::timeout(self.timeout, client.request(req)).await {
+            Ok(Ok(res)) if !res.status().is_success() => warn!("otlp collector answered {}", res.status()),
+            Ok(Err(e)) => warn!("can not export spans: {}", e),
+            Err(_) => warn!("can not export spans: collector did not answer in {:?}", self.timeout),
+            _ => {}
+        }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer where S: Subscriber + for<'a> LookupSpan<'a> {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut attributes = Vec::new();
+        attrs.record(&mut Fields(&mut attributes));
+        let parent = span.parent().and_then(|p| {
+            p.extensions().get::<SpanData>().map(|d| (d.trace_id, d.span_id))
+        });
+        let (trace_id, span_id, parent_id) = match attribute_id::<16>(&attributes, "trace_id") {
+            Some(trace_id) => (
+                trace_id,
+                attribute_id::<8>(&attributes, "span_id").unwrap_or_else(rand::random),
+                attribute_id::<8>(&attributes, "parent_id"),
+            ),
+            None => match parent {
+                Some((trace_id, parent_id)) => (trace_id, rand::random(), Some(parent_id)),
+                None => (rand::random(), rand::random(), None),
+            }
+        };
+        span.extensions_mut().insert(SpanData {
+            name: attrs.metadata().name(),
+            trace_id,
+            span_id,
+            parent_id,
+            start: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut Fields(&mut data.attributes));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(data) = span.extensions_mut().remove::<SpanData>() {
+                if let Err(TrySendError::Full(_)) = self.tx.try_send(Message::Span(data, SystemTime::now())) {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+
+#[test]
+fn test_otlp_export() {
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // collector stub recording the posted payloads
+    let received = Arc::new(Mutex::new(Vec::<Value>::new()));
+    let store = received.clone();
+    let (addr_tx, addr_rx) = sync_channel(1);
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async move {
+            let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+                let store = store.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let store = store.clone();
+                        async move {
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            store.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+                            Ok::<_, Infallible>(Response::new(Body::empty()))
+                        }
+                    }))
+                }
+            }));
+            addr_tx.send(server.local_addr()).unwrap();
+            server.await.unwrap();
+        });
+    });
+    let addr = addr_rx.recv().unwrap();
+
+    let (layer, handle) = OtlpExporter::new(&format!("http://{}/v1/traces", addr))
+        .service_name("test")
+        .build()
+        .unwrap();
+    let trace = crate::http::trace::TraceContext::parse(
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", None).unwrap();
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+        let request = trace.request_span(&Method::GET, "/user/1");
+        let _entered = request.enter();
+        crate::pipeline::link::stage_span(Some("load_user")).in_scope(|| {});
+        request.record("status", 200);
+    });
+    handle.flush();
+
+    let received = received.lock().unwrap();
+    let spans: Vec<&Value> = received.iter()
+        .flat_map(|p| p["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap())
+        .collect();
+    assert_eq!(spans.len(), 2);
+    let stage = spans.iter().find(|s| s["name"] == "stage").unwrap();
+    let request = spans.iter().find(|s| s["name"] == "request").unwrap();
+    assert_eq!(request["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(request["spanId"], trace.span_id());
+    assert_eq!(request["parentSpanId"], "00f067aa0ba902b7");
+    assert_eq!(stage["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(stage["parentSpanId"], trace.span_id());
+    assert!(request["attributes"].as_array().unwrap().iter()
+        .any(|a| a["key"] == "status" && a["value"]["stringValue"] == "200"));
+    assert_eq!(received[0]["resourceSpans"][0]["resource"]["attributes"][0]["value"]["stringValue"], "test");
+    assert_eq!(handle.dropped(), 0);
+
+    // a collector that never answers bounds the flush by the timeout
+    let stuck = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let (layer, handle) = OtlpExporter::new(&format!("http://{}/v1/traces", stuck.local_addr().unwrap()))
+        .timeout(Duration::from_millis(100))
+        .capacity(1)
+        .build()
+        .unwrap();
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+        for _ in 0..3 {
+            crate::pipeline::link::stage_span(Some("load_user")).in_scope(|| {});
+        }
+    });
+    let start = Instant::now();
+    handle.flush();
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
@@ -0,0 +1,120 @@
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Once;
+use std::task::{Context, Poll};
+
+use log::error;
+
+thread_local! {
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    static LAST_PANIC: RefCell<Option<(String, String)>> = const { RefCell::new(None) };
+}
+
+static HOOK: Once = Once::new();
+
+// What the Router does when a handler panics.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OnPanic {
+    // log it and answer 500
+    #[default]
+    Respond,
+    // log it and abort the process, for crash-only deployments
+    Abort,
+}
+
+/**
+ * put in the extensions of the 500 answered for a panicking handler,
+ * so that middlewares can tell it from other errors.
+ **/
+#[derive(Clone, Debug)]
+pub struct Panicked {
+    pub message: String,
+}
+
+// Panics caught by CatchPanic are recorded with their backtrace instead of being printed.
+fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING.with(|c| c.get()) {
+                return previous(info);
+            }
+            let payload = info.payload();
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Box<dyn Any>".to_string());
+            let message = match info.location() {
+                Some(l) => format!("{} at {}:{}", message, l.file(), l.line()),
+                None => message,
+            };
+            let backtrace = Backtrace::force_capture().to_string();
+            LAST_PANIC.with(|p| *p.borrow_mut() = Some((message, backtrace)));
+        }));
+    });
+}
+
+// Future turning a panic of `inner` into an error carrying its message.
+pub(crate) struct CatchPanic<'a, T> {
+    inner: Pin<Box<dyn Future<Output=T> + Send + 'a>>,
+}
+
+impl<'a, T> CatchPanic<'a, T> {
+    pub(crate) fn new(inner: Pin<Box<dyn Future<Output=T> + Send + 'a>>) -> Self {
+        install_hook();
+        CatchPanic { inner }
+    }
+}
+
+impl<'a, T> Future for CatchPanic<'a, T> {
+    type Output = Result<T, Panicked>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let was = CATCHING.with(|c| c.replace(true));
+        let polled = panic::catch_unwind(AssertUnwindSafe(|| self.inner.as_mut().poll(cx)));
+        CATCHING.with(|c| c.set(was));
+        match polled {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(t)) => Poll::Ready(Ok(t)),
+            Err(_) => {
+                let (message, backtrace) = LAST_PANIC.with(|p| p.borrow_mut().take())
+                    .unwrap_or_else(|| ("unknown panic".to_string(), String::new()));
+                error!("handler panicked: {}\n{}", message, backtrace);
+                Poll::Ready(Err(Panicked { message }))
+            }
+        }
+    }
+}
+
+
+#[tokio::test]
+async fn test_panic() {
+    use hyper::{Body, Method, Request, StatusCode};
+    use crate::http::handler::{Filter, GET};
+    use crate::http::request_id::RequestIds;
+    use crate::http::router::Router;
+    let mut r = Router::new();
+    r.add(GET().eq("/boom").handle_request()
+        .then(|_| -> &'static str { serde_json::from_str::<u32>("x").map(|_| "").unwrap() })
+        .ok());
+    r.add(GET().eq("/async").handle_request()
+        .then_async(|_| async {
+            tokio::task::yield_now().await;
+            panic!("later");
+        })
+        .then(|()| "")
+        .ok());
+    r.wrap(RequestIds::new());
+
+    let req = Request::get("/boom").header("x-request-id", "r-1").body(Body::empty()).unwrap();
+    let res = r.process(Method::GET, "/boom".to_string(), req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(res.extensions().get::<Panicked>().unwrap().message.contains("called `Result::unwrap()`"));
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "internal server error (request id: r-1)");
+
+    let res = r.process(Method::GET, "/async".to_string(), Request::new(Body::empty())).await.unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(res.extensions().get::<Panicked>().unwrap().message.starts_with("later at src/http/panic.rs"));
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::http::middleware::{Middleware, Next};
+use crate::http::request_id::RequestId;
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/**
+ * RFC 9457 problem details. a stage can fail with one to choose every member,
+ * error responses of the pipeline also carry the one describing them in their
+ * extensions, rendered as `application/problem+json` by the ProblemDetails middleware.
+ **/
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(flatten)]
+    pub extensions: Map<String, Value>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode) -> Self {
+        Problem {
+            type_uri: "about:blank".to_string(),
+            title: None,
+            status: status.as_u16(),
+            detail: None,
+            instance: None,
+            extensions: Map::new(),
+        }
+    }
+
+    pub fn type_uri(mut self: Self, uri: &str) -> Self {
+        self.type_uri = uri.to_string();
+        self
+    }
+
+    pub fn title(mut self: Self, title: &str) -> Self {
+        self.title = Some(title.to_string());
+        self
+    }
+
+    pub fn detail(mut self: Self, detail: &str) -> Self {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    pub fn instance(mut self: Self, instance: &str) -> Self {
+        self.instance = Some(instance.to_string());
+        self
+    }
+
+    // Values failing to serialize are left out.
+    pub fn extension<V: Serialize>(mut self: Self, name: &str, value: V) -> Self {
+        if let Ok(value) = serde_json::to_value(value) {
+            self.extensions.insert(name.to_string(), value);
+        }
+        self
+    }
+
+    pub fn status_code(self: &Self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    pub fn response(self: &Self) -> HyperResp {
+        let body = serde_json::to_vec(self).unwrap_or_default();
+        let mut res = Response::new(Body::from(body));
+        *res.status_mut() = self.status_code();
+        res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        res
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = self.status_code().canonical_reason().unwrap_or("error");
+        write!(f, "{}", self.detail.as_deref().or(self.title.as_deref()).unwrap_or(reason))
+    }
+}
+
+impl std::error::Error for Problem {}
+
+/**
+ * middleware answering every error response, status 400 and above, with
+ * problem details: the Problem the response carries, or one built from its
+ * status with its body as the detail, e.g. for routing errors or limits.
+ * the instance defaults to the request path, the request id becomes the
+ * `request_id` member, headers like `WWW-Authenticate` or `Retry-After` are kept.
+ **/
+#[derive(Default)]
+pub struct ProblemDetails {
+    types: HashMap<String, String>,
+    by_status: HashMap<StatusCode, String>,
+}
+
+impl ProblemDetails {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * title of the problems of type `uri`, and the type given to the
+     * problems of `status` that have none.
+     **/
+    pub fn register(mut self: Self, uri: &str, status: StatusCode, title: &str) -> Self {
+        self.types.insert(uri.to_string(), title.to_string());
+        self.by_status.insert(status, uri.to_string());
+        self
+    }
+
+    fn complete(self: &Self, mut problem: Problem, path: &str, request_id: Option<RequestId>) -> Problem {
+        if problem.type_uri == "about:blank" {
+            if let Some(uri) = self.by_status.get(&problem.status_code()) {
+                problem.type_uri = uri.clone();
+            }
+        }
+        if problem.title.is_none() {
+            problem.title = match self.types.get(&problem.type_uri) {
+                Some(title) => Some(title.clone()),
+                None if problem.type_uri == "about:blank" => problem.status_code().canonical_reason().map(str::to_string),
+                None => None,
+            };
+        }
+        if problem.instance.is_none() {
+            problem.instance = Some(path.to_string());
+        }
+        if let Some(RequestId(id)) = request_id {
+            problem.extensions.entry("request_id").or_insert(Value::from(id));
+        }
+        problem
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for ProblemDetails {
+    async fn handle(self: &Self, req: HyperReq, next: Next<'_>) -> hyper::Result<HyperResp> {
+        let path = req.uri().path().to_string();
+        let request_id = req.extensions().get::<RequestId>().cloned();
+        let res = next.run(req).await?;
+        let rendered = res.headers().get(header::CONTENT_TYPE).is_some_and(|v| v.as_bytes().starts_with(PROBLEM_JSON.as_bytes()));
+        if res.status().as_u16() < 400 || rendered {
+            return Ok(res);
+        }
+        let (mut parts, body) = res.into_parts();
+        let problem = match parts.extensions.remove::<Problem>() {
+            Some(problem) => problem,
+            None => {
+                let body = hyper::body::to_bytes(body).await?;
+                let detail = String::from_utf8_lossy(&body);
+                let problem = Problem::new(parts.status);
+                if detail.trim().is_empty() { problem } else { problem.detail(detail.trim()) }
+            }
+        };
+        let rendered = self.complete(problem, &path, request_id).response();
+        let (rendered, body) = rendered.into_parts();
+        parts.status = rendered.status;
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        Ok(Response::from_parts(parts, body))
+    }
+}
+
+
+#[tokio::test]
+async fn test_problem_details() {
+    use hyper::Method;
+    use serde::Deserialize;
+    use crate::http::handler::{Filter, GET, POST};
+    use crate::http::router::Router;
+    use crate::http::validate::length;
+
+    #[derive(Deserialize)]
+    struct Order {
+        item: String,
+    }
+    crate::validate!(Order {
+        item: [length(1, 10)],
+    });
+    let mut r = Router::new();
+    r.add(POST().eq("/orders").handle_request().parse_json::<Order>().validate().then(|o| o.item).ok());
+    r.add(GET().eq("/credit").handle_request().then_result(|_| -> Result<String, crate::pipeline::link::Error> {
+        Err(Problem::new(StatusCode::FORBIDDEN)
+            .type_uri("https://example.com/probs/out-of-credit")
+            .detail("your balance is 30, but that costs 50")
+            .extension("balance", 30)
+            .into())
+    }).ok());
+    r.add(GET().eq("/admin").require_scope("admin").handle_request().then(|_| "secret").ok());
+    r.add(GET().eq("/broken").handle_request().then_result(|_| -> Result<String, crate::pipeline::link::Error> { Err("db down".into()) }).ok());
+    r.wrap(ProblemDetails::new().register("https://example.com/probs/out-of-credit", StatusCode::PAYMENT_REQUIRED, "You do not have enough credit."));
+
+    let problem = |res: HyperResp| async move {
+        assert_eq!(res.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+        let status = res.status();
+        let body: Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["status"], status.as_u16());
+        body
+    };
+    let get = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+    let res = r.process(Method::GET, "/missing".to_string(), get("/missing")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body = problem(res).await;
+    assert_eq!((body["type"].as_str(), body["title"].as_str(), body["instance"].as_str()), (Some("about:blank"), Some("Not Found"), Some("/missing")));
+
+    let post = |body: &'static str| Request::post("/orders").body(Body::from(body)).unwrap();
+    let res = r.process(Method::POST, "/orders".to_string(), post("{")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert!(problem(res).await["detail"].as_str().unwrap().starts_with("invalid JSON body"));
+    let res = r.process(Method::POST, "/orders".to_string(), post(r#"{"item": ""}"#)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = problem(res).await;
+    assert_eq!(body["title"], "Unprocessable Entity");
+    assert_eq!(body["errors"][0]["field"], "item");
+
+    let res = r.process(Method::GET, "/credit".to_string(), get("/credit")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(problem(res).await, serde_json::json!({
+        "type": "https://example.com/probs/out-of-credit",
+        "title": "You do not have enough credit.",
+        "status": 403,
+        "detail": "your balance is 30, but that costs 50",
+        "instance": "/credit",
+        "balance": 30,
+    }));
+
+    let res = r.process(Method::GET, "/admin".to_string(), get("/admin")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(problem(res).await["detail"], "authentication required");
+
+    let res = r.process(Method::GET, "/broken".to_string(), get("/broken")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(problem(res).await["detail"], "stage 1: db down");
+
+    // without the middleware errors stay plain text
+    let mut plain = Router::new();
+    plain.add(GET().eq("/credit").handle_request().then_result(|_| -> Result<String, crate::pipeline::link::Error> {
+        Err(Problem::new(StatusCode::FORBIDDEN).detail("no credit").into())
+    }).ok());
+    let res = plain.process(Method::GET, "/credit".to_string(), get("/credit")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "no credit");
+}
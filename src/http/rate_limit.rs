@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::time::Instant;
+
+use crate::http::authz::Principal;
+use crate::http::middleware::{MatchedRoute, Middleware, Next};
+use crate::http::server::PeerAddr;
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+type Extractor = Box<dyn Fn(&HyperReq) -> Option<String> + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quota {
+    // `burst` requests at once, refilled at `per_second`.
+    TokenBucket { burst: u64, per_second: f64 },
+    // `limit` requests over any `window`, weighted from the previous fixed window.
+    SlidingWindow { limit: u64, window: Duration },
+}
+
+impl Quota {
+    // Panics unless `per_second` is positive, use a long sliding window for a fixed allowance.
+    pub fn token_bucket(burst: u64, per_second: f64) -> Self {
+        assert!(per_second > 0.0 && per_second.is_finite(), "rate limit: per_second must be positive");
+        Quota::TokenBucket { burst, per_second }
+    }
+
+    // Panics unless `limit` and `window` are positive.
+    pub fn sliding_window(limit: u64, window: Duration) -> Self {
+        assert!(!window.is_zero() && limit > 0, "rate limit: limit and window must be positive");
+        Quota::SlidingWindow { limit, window }
+    }
+}
+
+// Outcome of taking one request from a quota.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    // until the quota is fully available again
+    pub reset: Duration,
+    // until a refused request may succeed
+    pub retry_after: Duration,
+}
+
+/**
+ * state of the quotas, shared by every instance of the service when it is
+ * external. `key` identifies both the rule and the client.
+ **/
+#[async_trait::async_trait]
+pub trait RateLimitStore: Send + Sync {
+    async fn acquire(self: &Self, key: &str, quota: &Quota) -> Decision;
+}
+
+enum State {
+    Bucket { tokens: f64, last: Instant },
+    Window { start: Instant, previous: u64, current: u64 },
+}
+
+/**
+ * store keeping the quotas of this process. keys idle for longer than
+ * `idle` are evicted from time to time.
+ **/
+pub struct MemoryStore {
+    states: Mutex<HashMap<String, (State, Instant)>>,
+    calls: AtomicU64,
+    idle: Duration,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            states: Mutex::new(HashMap::new()),
+            calls: AtomicU64::new(0),
+            idle: Duration::from_secs(600),
+        }
+    }
+
+    pub fn idle(mut self: Self, idle: Duration) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    fn fresh(quota: &Quota, now: Instant) -> State {
+        match quota {
+            Quota::TokenBucket { burst, .. } => State::Bucket { tokens: *burst as f64, last: now },
+            Quota::SlidingWindow { .. } => State::Window { start: now, previous: 0, current: 0 },
+        }
+    }
+
+    fn take(state: &mut State, quota: &Quota, now: Instant) -> Decision {
+        match (state, quota) {
+            (State::Bucket { tokens, last }, Quota::TokenBucket { burst, per_second }) => {
+                let burst = *burst as f64;
+                *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * per_second).min(burst);
+                *last = now;
+                let allowed = *tokens >= 1.0;
+                if allowed {
+                    *tokens -= 1.0;
+                }
+                Decision {
+                    allowed,
+                    limit: burst as u64,
+                    remaining: tokens.floor() as u64,
+                    reset: secs((burst - *tokens) / per_second),
+                    retry_after: secs((1.0 - *tokens).max(0.0) / per_second),
+                }
+            }
+            (State::Window { start, previous, current }, Quota::SlidingWindow { limit, window }) => {
+                let windows = (now.duration_since(*start).as_nanos() / window.as_nanos().max(1)) as u32;
+                if windows > 0 {
+                    *previous = if windows == 1 { *current } else { 0 };
+                    *current = 0;
+                    *start += *window * windows;
+                }
+                let elapsed = now.duration_since(*start);
+                let weight = 1.0 - elapsed.as_secs_f64() / window.as_secs_f64();
+                let estimated = (*previous as f64 * weight).floor() as u64 + *current;
+                let allowed = estimated < *limit;
+                if allowed {
+                    *current += 1;
+                }
+                let until_next = window.saturating_sub(elapsed);
+                Decision {
+                    allowed,
+                    limit: *limit,
+                    remaining: limit.saturating_sub(estimated + allowed as u64),
+                    reset: until_next + if *current > 0 { *window } else { Duration::ZERO },
+                    retry_after: until_next,
+                }
+            }
+            // a store shared by rules giving the key another kind of quota, start over with this one
+            (state, quota) => {
+                *state = Self::fresh(quota, now);
+                Self::take(state, quota, now)
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn acquire(self: &Self, key: &str, quota: &Quota) -> Decision {
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        if self.calls.fetch_add(1, Ordering::Relaxed) % 1024 == 1023 {
+            states.retain(|_, (_, touched)| now.duration_since(*touched) < self.idle);
+        }
+        let (state, touched) = states.entry(key.to_string()).or_insert_with(|| (Self::fresh(quota, now), now));
+        *touched = now;
+        Self::take(state, quota, now)
+    }
+}
+
+// What identifies a client, requests without it are not limited by the rule.
+pub enum Key {
+    Global,
+    PeerIp,
+    // lowercase header name
+    Header(&'static str),
+    Custom(Extractor),
+}
+
+impl Key {
+    pub fn api_key(header: &'static str) -> Self {
+        Key::Header(header)
+    }
+
+    // The Principal<P> put in the extensions by the Authenticate middleware, added before this one.
+    // the authentication stages run after every middleware.
+    pub fn principal<P: Display + Send + Sync + 'static>() -> Self {
+        Key::Custom(Box::new(|req| req.extensions().get::<Principal<P>>().map(|p| p.0.to_string())))
+    }
+
+    pub fn custom<F>(f: F) -> Self where F: Fn(&HyperReq) -> Option<String> + Send + Sync + 'static {
+        Key::Custom(Box::new(f))
+    }
+
+    fn of(self: &Self, req: &HyperReq) -> Option<String> {
+        match self {
+            Key::Global => Some(String::new()),
+            Key::PeerIp => req.extensions().get::<PeerAddr>().map(|p| p.0.ip().to_string()),
+            Key::Header(name) => req.headers().get(*name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            Key::Custom(f) => f(req),
+        }
+    }
+}
+
+struct Rule {
+    route: Option<String>,
+    key: Key,
+    quota: Quota,
+}
+
+/**
+ * middleware refusing requests over quota with 429. every rule applying to
+ * the matched route takes one request, the most constraining one is
+ * reported with the RateLimit-Limit/Remaining/Reset headers.
+ **/
+pub struct RateLimit {
+    rules: Vec<Rule>,
+    store: Box<dyn RateLimitStore>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        RateLimit {
+            rules: Vec::new(),
+            store: Box::new(MemoryStore::new()),
+        }
+    }
+
+    pub fn store(mut self: Self, store: impl RateLimitStore + 'static) -> Self {
+        self.store = Box::new(store);
+        self
+    }
+
+    // Applies to every request.
+    pub fn limit(mut self: Self, key: Key, quota: Quota) -> Self {
+        self.rules.push(Rule { route: None, key, quota });
+        self
+    }
+
+    // Applies to the requests matched by the route `pattern`, each route having its own quota.
+    pub fn limit_route(mut self: Self, pattern: &str, key: Key, quota: Quota) -> Self {
+        self.rules.push(Rule { route: Some(pattern.to_string()), key, quota });
+        self
+    }
+}
+
+// a bucket built without refill never frees up
+fn secs(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+}
+
+fn ceil_secs(d: Duration) -> u64 {
+    d.as_secs().saturating_add((d.subsec_nanos() > 0) as u64)
+}
+
+#[async_trait::async_trait]
+impl Middleware for RateLimit {
+    async fn handle(self: &Self, req: HyperReq, next: Next<'_>) -> hyper::Result<HyperResp> {
+        let route = req.extensions().get::<MatchedRoute>().map(|r| r.0.clone());
+        let mut tightest: Option<Decision> = None;
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.route.is_some() && rule.route != route {
+                continue;
+            }
+            let key = match rule.key.of(&req) {
+                Some(key) => format!("{}:{}", i, key),
+                None => continue,
+            };
+            let decision = self.store.acquire(&key, &rule.quota).await;
+            let tighter = match &tightest {
+                None => true,
+                Some(t) => (!decision.allowed && t.allowed)
+                    || (decision.allowed == t.allowed && decision.remaining < t.remaining),
+            };
+            if tighter {
+                tightest = Some(decision);
+            }
+        }
+        let decision = match tightest {
+            None => return next.run(req).await,
+            Some(d) => d,
+        };
+        let mut res = if decision.allowed {
+            next.run(req).await?
+        } else {
+            let mut res = Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .body(Body::from("too many requests"))
+                .unwrap();
+            res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(ceil_secs(decision.retry_after).max(1)));
+            res
+        };
+        let headers = res.headers_mut();
+        headers.insert(HeaderName::from_static("ratelimit-limit"), HeaderValue::from(decision.limit));
+        headers.insert(HeaderName::from_static("ratelimit-remaining"), HeaderValue::from(decision.remaining));
+        headers.insert(HeaderName::from_static("ratelimit-reset"), HeaderValue::from(ceil_secs(decision.reset)));
+        Ok(res)
+    }
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limit() {
+    use hyper::Method;
+    use crate::http::handler::{Filter, GET};
+    use crate::http::router::Router;
+    let mut r = Router::new();
+    r.add(GET().eq("/a").handle_request().then(|_| "a").ok());
+    r.add(GET().eq("/login").handle_request().then(|_| "login").ok());
+    r.wrap(RateLimit::new()
+        .limit(Key::PeerIp, Quota::token_bucket(3, 1.0))
+        .limit_route("/login", Key::api_key("x-api-key"), Quota::sliding_window(2, Duration::from_secs(10))));
+
+    let call = |path: &'static str, ip: [u8; 4], key: Option<&'static str>| {
+        let mut req = Request::get(path);
+        if let Some(key) = key {
+            req = req.header("x-api-key", key);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        req.extensions_mut().insert(PeerAddr((ip, 4000).into()));
+        r.process(Method::GET, path.to_string(), req)
+    };
+
+    for remaining in ["2", "1", "0"] {
+        let res = call("/a", [10, 0, 0, 1], None).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["ratelimit-limit"], "3");
+        assert_eq!(res.headers()["ratelimit-remaining"], remaining);
+    }
+    let res = call("/a", [10, 0, 0, 1], None).await.unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers()[header::RETRY_AFTER], "1");
+    // another client has its own bucket
+    assert_eq!(call("/a", [10, 0, 0, 2], None).await.unwrap().status(), StatusCode::OK);
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert_eq!(call("/a", [10, 0, 0, 1], None).await.unwrap().status(), StatusCode::OK);
+
+    // the login route is limited by api key on top of the peer ip
+    assert_eq!(call("/login", [10, 0, 0, 3], Some("k")).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(call("/login", [10, 0, 0, 4], Some("k")).await.unwrap().status(), StatusCode::OK);
+    let res = call("/login", [10, 0, 0, 5], Some("k")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers()["ratelimit-limit"], "2");
+    assert_eq!(res.headers()[header::RETRY_AFTER], "10");
+    tokio::time::advance(Duration::from_secs(15)).await;
+    // half of the previous window still counts
+    assert_eq!(call("/login", [10, 0, 0, 5], Some("k")).await.unwrap().status(), StatusCode::OK);
+    assert_eq!(call("/login", [10, 0, 0, 5], Some("k")).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+
+    assert!(std::panic::catch_unwind(|| Quota::token_bucket(1, 0.0)).is_err());
+    assert!(std::panic::catch_unwind(|| Quota::sliding_window(1, Duration::ZERO)).is_err());
+    assert!(std::panic::catch_unwind(|| Quota::sliding_window(0, Duration::from_secs(1))).is_err());
+    // a bucket without refill built by hand refuses once empty instead of panicking
+    let store = MemoryStore::new();
+    let fixed = Quota::TokenBucket { burst: 1, per_second: 0.0 };
+    assert!(store.acquire("k", &fixed).await.allowed);
+    let refused = store.acquire("k", &fixed).await;
+    assert!(!refused.allowed);
+    assert_eq!(ceil_secs(refused.retry_after), u64::MAX);
+    // the same key under another kind of quota starts over instead of panicking
+    let window = Quota::sliding_window(2, Duration::from_secs(10));
+    assert_eq!(store.acquire("k", &window).await.remaining, 1);
+    assert!(store.acquire("k", &fixed).await.allowed);
+    assert!(!store.acquire("k", &fixed).await.allowed);
+}
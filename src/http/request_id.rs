@@ -0,0 +1,157 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderName, HeaderValue, InvalidHeaderName};
+use hyper::{Body, Request, Response};
+
+use crate::http::middleware::{Middleware, Next};
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/**
+ * id of the request being handled, stored in the request extensions by
+ * the RequestIds middleware.
+ **/
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+// Id of the request handled by the current task, used to tag log lines.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+pub enum Generator {
+    Uuid,
+    Ulid,
+}
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+impl Generator {
+    pub fn generate(self: &Self) -> String {
+        match self {
+            Generator::Uuid => {
+                let mut b: [u8; 16] = rand::random();
+                b[6] = (b[6] & 0x0f) | 0x40;
+                b[8] = (b[8] & 0x3f) | 0x80;
+                let mut s = String::with_capacity(36);
+                for (i, byte) in b.iter().enumerate() {
+                    if matches!(i, 4 | 6 | 8 | 10) {
+                        s.push('-');
+                    }
+                    let _ = write!(s, "{:02x}", byte);
+                }
+                s
+            }
+            Generator::Ulid => {
+                let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                let random = rand::random::<u128>() >> 48;
+                let value = (millis & ((1 << 48) - 1)) << 80 | random;
+                (0..26).rev()
+                    .map(|i| CROCKFORD[((value >> (i * 5)) & 0x1f) as usize] as char)
+                    .collect()
+            }
+        }
+    }
+}
+
+/**
+ * middleware taking the request id from a header or generating one.
+ * the id is put in the request extensions, scoped to the task for logging
+ * and echoed in the response, whose extensions also carry it for the
+ * middlewares it is wrapped in, like AccessLog.
+ **/
+pub struct RequestIds {
+    header: HeaderName,
+    generator: Generator,
+}
+
+impl Default for RequestIds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestIds {
+    pub fn new() -> Self {
+        RequestIds {
+            header: HeaderName::from_static("x-request-id"),
+            generator: Generator::Uuid,
+        }
+    }
+
+    // Header carrying the id, in any case, e.g. `X-Correlation-Id`.
+    pub fn header(mut self: Self, name: &str) -> Result<Self, InvalidHeaderName> {
+        self.header = HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes())?;
+        Ok(self)
+    }
+
+    pub fn generator(mut self: Self, generator: Generator) -> Self {
+        self.generator = generator;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for RequestIds {
+    async fn handle(self: &Self, mut req: HyperReq, next: Next<'_>) -> hyper::Result<HyperResp> {
+        let id = req.headers().get(&self.header)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= 128)
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| self.generator.generate());
+        req.extensions_mut().insert(RequestId(id.clone()));
+        tracing::Span::current().record("request_id", id.as_str());
+        let mut res = REQUEST_ID.scope(id.clone(), next.run(req)).await;
+        if let Ok(resp) = &mut res {
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                resp.headers_mut().insert(self.header.clone(), value);
+            }
+            // for the middlewares wrapped around this one, like AccessLog
+            resp.extensions_mut().insert(RequestId(id));
+        }
+        res
+    }
+}
+
+
+#[tokio::test]
+async fn test_request_id() {
+    use hyper::Method;
+    use crate::http::handler::{Filter, GET};
+    use crate::http::router::Router;
+    let mut r = Router::new();
+    r.add(GET().eq("/id").handle_request()
+        .then(|req| {
+            let from_ext = req.extensions().get::<RequestId>().unwrap().0.clone();
+            assert_eq!(Some(from_ext.clone()), current());
+            from_ext
+        })
+        .ok());
+    r.add(GET().eq("/fail").handle_request()
+        .then_result(|_| -> Result<String, crate::pipeline::link::Error> { Err("boom".into()) })
+        .ok());
+    r.wrap(RequestIds::new().header("X-Correlation-Id").unwrap());
+
+    let req = Request::get("/id").header("x-correlation-id", "abc").body(Body::empty()).unwrap();
+    let res = r.process(Method::GET, "/id".to_string(), req).await.unwrap();
+    assert_eq!(res.headers()["x-correlation-id"], "abc");
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "abc");
+
+    let res = r.process(Method::GET, "/fail".to_string(), Request::new(Body::empty())).await.unwrap();
+    let id = res.headers()["x-correlation-id"].to_str().unwrap().to_string();
+    assert_eq!(id.len(), 36);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    assert!(String::from_utf8(body.to_vec()).unwrap().contains(&id));
+
+    assert!(RequestIds::new().header("x request id").is_err());
+
+    let ulid = Generator::Ulid.generate();
+    assert_eq!(ulid.len(), 26);
+    assert!(ulid.bytes().all(|c| CROCKFORD.contains(&c)));
+}
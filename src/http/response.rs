@@ -0,0 +1,268 @@
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+
+use crate::http::error::{error_response, HttpError};
+use crate::http::problem::Problem;
+use crate::pipeline::link;
+
+type HyperResp = Response<Body>;
+
+/**
+ * outputs the `respond` stage turns into the response, e.g.
+ * `(StatusCode::CREATED, [("location", url)], Json(order))`.
+ **/
+pub trait IntoResponse {
+    fn into_response(self: Self) -> HyperResp;
+}
+
+// Headers added by a tuple response, names or values that are not valid are ignored.
+pub trait IntoHeaders {
+    fn into_headers(self: Self, headers: &mut HeaderMap);
+}
+
+fn with_content_type(body: Body, content_type: &'static str) -> HyperResp {
+    let mut res = Response::new(body);
+    res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    res
+}
+
+// Serialized as the JSON body, a value failing to serialize gives a 500.
+pub struct Json<T>(pub T);
+
+// 204 with no body.
+pub struct NoContent;
+
+pub struct Redirect {
+    status: StatusCode,
+    location: String,
+}
+
+impl Redirect {
+    // 303, the client follows with a GET.
+    pub fn to(location: &str) -> Self {
+        Self::with(StatusCode::SEE_OTHER, location)
+    }
+
+    // 307, the client repeats the request with the same method.
+    pub fn temporary(location: &str) -> Self {
+        Self::with(StatusCode::TEMPORARY_REDIRECT, location)
+    }
+
+    // 308, the same and clients may remember it.
+    pub fn permanent(location: &str) -> Self {
+        Self::with(StatusCode::PERMANENT_REDIRECT, location)
+    }
+
+    fn with(status: StatusCode, location: &str) -> Self {
+        Redirect {
+            status,
+            location: location.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for HyperResp {
+    fn into_response(self: Self) -> HyperResp {
+        self
+    }
+}
+
+impl IntoResponse for StatusCode {
+    fn into_response(self: Self) -> HyperResp {
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = self;
+        res
+    }
+}
+
+impl IntoResponse for () {
+    fn into_response(self: Self) -> HyperResp {
+        Response::new(Body::empty())
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self: Self) -> HyperResp {
+        with_content_type(Body::from(self), "text/plain; charset=utf-8")
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self: Self) -> HyperResp {
+        with_content_type(Body::from(self), "text/plain; charset=utf-8")
+    }
+}
+
+impl IntoResponse for Bytes {
+    fn into_response(self: Self) -> HyperResp {
+        with_content_type(Body::from(self), "application/octet-stream")
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self: Self) -> HyperResp {
+        with_content_type(Body::from(self), "application/octet-stream")
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self: Self) -> HyperResp {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => with_content_type(Body::from(body), "application/json"),
+            Err(e) => error_response(&e.into(), None),
+        }
+    }
+}
+
+impl IntoResponse for NoContent {
+    fn into_response(self: Self) -> HyperResp {
+        StatusCode::NO_CONTENT.into_response()
+    }
+}
+
+impl IntoResponse for Redirect {
+    fn into_response(self: Self) -> HyperResp {
+        (self.status, [(header::LOCATION.as_str(), self.location)], ()).into_response()
+    }
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self: Self) -> HyperResp {
+        error_response(&self.into(), None)
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self: Self) -> HyperResp {
+        error_response(&self.into(), None)
+    }
+}
+
+impl IntoResponse for link::Error {
+    fn into_response(self: Self) -> HyperResp {
+        error_response(&self, None)
+    }
+}
+
+// None is a 404.
+impl<T: IntoResponse> IntoResponse for Option<T> {
+    fn into_response(self: Self) -> HyperResp {
+        match self {
+            Some(value) => value.into_response(),
+            None => error_response(&HttpError::new(StatusCode::NOT_FOUND, "not found").into(), None),
+        }
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self: Self) -> HyperResp {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(e) => e.into_response(),
+        }
+    }
+}
+
+impl<R: IntoResponse> IntoResponse for (StatusCode, R) {
+    fn into_response(self: Self) -> HyperResp {
+        let mut res = self.1.into_response();
+        *res.status_mut() = self.0;
+        res
+    }
+}
+
+impl<H: IntoHeaders, R: IntoResponse> IntoResponse for (StatusCode, H, R) {
+    fn into_response(self: Self) -> HyperResp {
+        let mut res = (self.0, self.2).into_response();
+        self.1.into_headers(res.headers_mut());
+        res
+    }
+}
+
+impl<K: AsRef<str>, V: AsRef<str>, const N: usize> IntoHeaders for [(K, V); N] {
+    fn into_headers(self: Self, headers: &mut HeaderMap) {
+        Vec::from(self).into_headers(headers)
+    }
+}
+
+impl<K: AsRef<str>, V: AsRef<str>> IntoHeaders for Vec<(K, V)> {
+    fn into_headers(self: Self, headers: &mut HeaderMap) {
+        for (name, value) in self {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_ref().as_bytes()), HeaderValue::from_str(value.as_ref())) {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+impl IntoHeaders for HeaderMap {
+    fn into_headers(self: Self, headers: &mut HeaderMap) {
+        headers.extend(self);
+    }
+}
+
+
+#[tokio::test]
+async fn test_respond() {
+    use hyper::{Method, Request};
+    use crate::http::handler::{Filter, GET, POST};
+    use crate::http::router::Router;
+
+    #[derive(Serialize)]
+    struct Order {
+        id: u32,
+    }
+    let mut r = Router::new();
+    r.add(POST().eq("/orders").handle_request()
+        .then(|_| (StatusCode::CREATED, [("location", "/orders/7".to_string())], Json(Order { id: 7 })))
+        .respond());
+    r.add(GET().start_with("/orders/").handle_request()
+        .then(|req| req.uri().path().trim_start_matches("/orders/").parse::<u32>().ok().filter(|id| *id == 7))
+        .then(|id| id.map(|id| Json(Order { id })))
+        .respond());
+    r.add(GET().eq("/old").handle_request().then(|_| Redirect::permanent("/new")).respond());
+    r.add(POST().eq("/ping").handle_request().then(|_| NoContent).respond());
+    r.add(GET().eq("/raw").handle_request().then(|_| Bytes::from_static(b"\x00\x01")).respond());
+    r.add(GET().eq("/maybe").handle_request()
+        .then(|req| -> Result<String, (StatusCode, &'static str)> {
+            match req.uri().query() {
+                Some(q) => Ok(q.to_string()),
+                None => Err((StatusCode::BAD_REQUEST, "query required")),
+            }
+        })
+        .respond());
+
+    let send = |method: Method, path: &'static str| {
+        let req = Request::builder().method(method.clone()).uri(path).body(Body::empty()).unwrap();
+        let r = &r;
+        async move {
+            let res = r.process(method, req.uri().path().to_string(), req).await.unwrap();
+            let (parts, body) = res.into_parts();
+            (parts.status, parts.headers, hyper::body::to_bytes(body).await.unwrap())
+        }
+    };
+    let (status, headers, body) = send(Method::POST, "/orders").await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(headers[header::LOCATION], "/orders/7");
+    assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+    assert_eq!(body, r#"{"id":7}"#);
+    let (status, _, body) = send(Method::GET, "/orders/7").await;
+    assert_eq!((status, body), (StatusCode::OK, Bytes::from(r#"{"id":7}"#)));
+    let (status, _, _) = send(Method::GET, "/orders/8").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, headers, _) = send(Method::GET, "/old").await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(headers[header::LOCATION], "/new");
+    let (status, _, body) = send(Method::POST, "/ping").await;
+    assert_eq!((status, body.len()), (StatusCode::NO_CONTENT, 0));
+    let (_, headers, body) = send(Method::GET, "/raw").await;
+    assert_eq!(headers[header::CONTENT_TYPE], "application/octet-stream");
+    assert_eq!(body, Bytes::from_static(b"\x00\x01"));
+    let (status, headers, body) = send(Method::GET, "/maybe?x").await;
+    assert_eq!((status, body), (StatusCode::OK, Bytes::from("x")));
+    assert_eq!(headers[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+    let (status, _, body) = send(Method::GET, "/maybe").await;
+    assert_eq!((status, body), (StatusCode::BAD_REQUEST, Bytes::from("query required")));
+}
@@ -1,16 +1,33 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 use hyper::{Body, Method, Request, Response, StatusCode};
+use crate::http::authz::RouteInfo;
+use crate::http::cors::Cors;
 use crate::http::handler::Handler;
+use crate::http::middleware::{Endpoint, MatchedRoute, Middleware, Next};
+use crate::http::panic::OnPanic;
 
 pub struct Router {
-    entries: HashMap<Method, Vec<Box<dyn Handler>>>,
+    entries: HashMap<Method, Vec<Arc<dyn Handler>>>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    cors: Option<Cors>,
+    on_panic: OnPanic,
 }
 
 
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Router {
     pub fn new() -> Self {
         Router {
-            entries: HashMap::new()
+            entries: HashMap::new(),
+            middlewares: Vec::new(),
+            cors: None,
+            on_panic: OnPanic::Respond,
         }
     }
 
@@ -25,18 +42,33 @@ impl Router {
     }
 
     pub fn add(self: &mut Self, handler: impl Handler + 'static) {
-        let method = handler.method();
-        let handler_box = Box::new(handler);
-        match self.entries.get_mut(&method) {
-            None => {
-                self.entries.insert(method, vec![handler_box]);
-            }
-            Some(l) => {
-                l.push(handler_box);
+        let handler: Arc<dyn Handler> = Arc::new(handler);
+        for method in handler.methods() {
+            match self.entries.get_mut(&method) {
+                None => {
+                    self.entries.insert(method, vec![handler.clone()]);
+                }
+                Some(l) => {
+                    l.push(handler.clone());
+                }
             }
         }
     }
 
+    // Middlewares run in the order they are added, the first one being the outermost.
+    pub fn wrap(self: &mut Self, middleware: impl Middleware + 'static) {
+        self.middlewares.push(Arc::new(middleware));
+    }
+
+    pub fn cors(self: &mut Self, cors: Cors) {
+        self.cors = Some(cors);
+    }
+
+    // Panics of handlers are logged and answered with 500 unless set to OnPanic::Abort.
+    pub fn on_panic(self: &mut Self, on_panic: OnPanic) {
+        self.on_panic = on_panic;
+    }
+
     pub fn merge(mut self: Self, other: Router) -> Self {
         for (method, handle)in other.entries {
            match self.entries.get_mut(&method) {
@@ -48,32 +80,62 @@ impl Router {
                }
            }
         }
+        self.middlewares.extend(other.middlewares);
+        self.cors = self.cors.or(other.cors);
         self
     }
 
-    pub async fn process(self: &Self, method: Method, path: String, body: Request<Body>) -> hyper::Result<Response<Body>> {
-        match self.entries.get(&method) {
-            None => Ok(Self::err_response(
-                StatusCode::METHOD_NOT_ALLOWED,
-                "method not allowed",
-            )),
-            Some(list_of_processor) => {
-                match list_of_processor.into_iter().filter(|p| p.test(&path)).nth(0) {
-                    None => Ok(Self::err_response(
-                        StatusCode::NOT_FOUND,
-                        "not found",
-                    )),
+    // Every route with the permissions it requires, for audits.
+    pub fn routes(self: &Self) -> Vec<RouteInfo> {
+        self.entries.iter()
+            .flat_map(|(method, handlers)| handlers.iter().map(move |h| RouteInfo {
+                method: method.clone(),
+                pattern: h.pattern().to_string(),
+                permissions: h.permissions(),
+            }))
+            .collect()
+    }
+
+    fn find(self: &Self, method: &Method, path: &str) -> Option<&dyn Handler> {
+        self.entries.get(method)
+            .and_then(|list_of_processor| list_of_processor.iter().find(|p| p.test(path)))
+            .map(|p| p.as_ref())
+    }
+
+    // Methods having a handler for `path`.
+    fn methods(self: &Self, path: &str) -> Vec<Method> {
+        self.entries.keys().filter(|m| self.find(m, path).is_some()).cloned().collect()
+    }
+
+    pub async fn process(self: &Self, method: Method, path: String, mut body: Request<Body>) -> hyper::Result<Response<Body>> {
+        let origin = self.cors.as_ref().and_then(|_| Cors::origin(&body));
+        // preflights for paths without a handler for the requested method go through normal dispatch
+        let preflight = self.cors.as_ref()
+            .filter(|_| Cors::is_preflight(&body))
+            .and_then(|cors| Some((cors, self.find(&Cors::requested_method(&body)?, &path)?)));
+        let endpoint = if let Some((cors, processor)) = preflight {
+            body.extensions_mut().insert(MatchedRoute(processor.pattern().to_string()));
+            Endpoint::Ready(cors.preflight(&body, &self.methods(&path)))
+        } else {
+            match self.entries.get(&method) {
+                None => Endpoint::Reject(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+                Some(_) => match self.find(&method, &path) {
+                    None => Endpoint::Reject(StatusCode::NOT_FOUND, "not found"),
                     Some(processor) => {
-                        match processor.proc(path, body).await {
-                            Ok(t) => Ok(t),
-                            Err(e) => Ok(Self::err_response(
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                e.to_string()
-                            ))
-                        }
+                        tracing::Span::current().record("route", processor.pattern());
+                        body.extensions_mut().insert(MatchedRoute(processor.pattern().to_string()));
+                        Endpoint::Handler(processor, path, self.on_panic)
                     }
                 }
             }
+        };
+        let mut res = Next {
+            middlewares: &self.middlewares,
+            endpoint,
+        }.run(body).await;
+        if let (Some(cors), false, Ok(resp)) = (&self.cors, preflight.is_some(), &mut res) {
+            cors.decorate(origin.as_ref(), resp);
         }
+        res
     }
-}
\ No newline at end of file
+}
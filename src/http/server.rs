@@ -1,5 +1,4 @@
-use std::convert::Infallible;
-/**
+/*
  * Licensed to the Apache Software Foundation (ASF) under one
  * or more contributor license agreements.  See the NOTICE file
  * distributed with this work for additional information
@@ -18,21 +17,30 @@ use std::convert::Infallible;
  */
 
 
+use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use futures_util::TryFutureExt;
-use hyper::server::conn::AddrIncoming;
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::server::Builder as HyperBuilder;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{self, Body, Method, Request, Response, Server};
 use log::{info, warn};
+use tracing::Instrument;
 
 use tokio::runtime::{Builder, Runtime};
 use tokio::sync::oneshot::{Receiver, Sender};
 
+use crate::http::health::Health;
 use crate::http::router::Router;
+use crate::http::trace::TraceContext;
+
+// Remote address of the connection a request came from, stored in the request extensions.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
 
 pub struct HttpServer {
     thread_pool: Runtime,
@@ -40,6 +48,9 @@ pub struct HttpServer {
     rx: Option<Receiver<()>>,
     addr: Option<SocketAddr>,
     router: Arc<Router>,
+    health: Option<Arc<Health>>,
+    draining: Arc<AtomicBool>,
+    drain_grace: Duration,
 }
 
 impl HttpServer {
@@ -65,13 +76,39 @@ impl HttpServer {
             rx: Some(rx),
             addr: None,
             router: Arc::new(router),
+            health: None,
+            draining: Arc::new(AtomicBool::new(false)),
+            drain_grace: Duration::ZERO,
         })
     }
 
+    // Serve the liveness, readiness and drain-state routes of `health`
+    // ahead of the router, must be called before `start`.
+    pub fn enable_health(&mut self, health: Health) {
+        self.health = Some(Arc::new(health.share_draining(self.draining.clone())));
+    }
+
+    // Flip readiness to failing without stopping, so that the orchestrator
+    // can take the instance out of rotation before `stop` is called.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
 
+    // Time `stop` keeps serving after flipping readiness, long enough for the
+    // orchestrator to poll it, e.g. a few readiness probe periods. zero by default.
+    pub fn drain_grace(&mut self, grace: Duration) {
+        self.drain_grace = grace;
+    }
+
+    // Drain, wait for the drain grace, then stop accepting and shut down.
     pub fn stop(self) {
+        self.drain();
+        if !self.drain_grace.is_zero() {
+            std::thread::sleep(self.drain_grace);
+        }
         let _ = self.tx.send(());
         self.thread_pool.shutdown_timeout(Duration::from_secs(10));
+        log::logger().flush();
     }
 
     // Return listening address, this may only be used for outer test
@@ -81,23 +118,38 @@ impl HttpServer {
         self.addr.unwrap()
     }
 
-    async fn handle_request(router: Arc<Router>, method: Method, path: String, req: Request<Body>)
+    async fn handle_request(router: Arc<Router>, health: Option<Arc<Health>>, method: Method, path: String, mut req: Request<Body>)
                             -> hyper::Result<Response<Body>> {
-        router.process(method, path, req).await
+        if let Some(h) = health.filter(|h| h.matches(&method, &path)) {
+            return Ok(h.respond(&path).await);
+        }
+        let trace = TraceContext::from_headers(req.headers());
+        let span = trace.request_span(&method, &path);
+        req.extensions_mut().insert(trace.clone());
+        let mut res = router.process(method, path, req).instrument(span.clone()).await;
+        if let Ok(resp) = &mut res {
+            span.record("status", resp.status().as_u16());
+            trace.inject(resp.headers_mut());
+        }
+        res
     }
 
     fn start_serve(&mut self, builder: HyperBuilder<AddrIncoming>) {
         // Start to serve.
         let router_out = self.router.clone();
-        let make_service = make_service_fn(move |_conn| {
+        let health_out = self.health.clone();
+        let make_service = make_service_fn(move |conn: &AddrStream| {
             let router = router_out.clone();
+            let health = health_out.clone();
+            let peer = PeerAddr(conn.remote_addr());
             async move {
                 // This is the request handler.
-                Ok::<_, Infallible>(service_fn(move |req| {
+                Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                    req.extensions_mut().insert(peer);
                     let path = req.uri().path().to_owned();
                     let method = req.method().to_owned();
                     let r = router.clone();
-                    Self::handle_request(r, method, path, req)
+                    Self::handle_request(r, health.clone(), method, path, req)
                 }))
             }
         });
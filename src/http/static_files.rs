@@ -0,0 +1,407 @@
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use futures_util::stream;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::http::accept::AcceptEncoding;
+use crate::http::handler::{Filter, Handler, Select};
+use crate::http::router::Router;
+
+type HyperResp = Response<Body>;
+type HyperReq = Request<Body>;
+
+const CHUNK: u64 = 64 * 1024;
+
+/**
+ * handler serving the files under a directory, mounted on a path prefix.
+ * supports conditional requests, single byte ranges, precompressed
+ * `.br`/`.gz` siblings and falling back to `index.html` for SPAs.
+ **/
+pub struct StaticFiles {
+    root: PathBuf,
+    mount: String,
+    pattern: String,
+    index: String,
+    spa_fallback: bool,
+    precompressed: bool,
+    cache_control: Option<HeaderValue>,
+}
+
+pub fn static_files(dir: impl Into<PathBuf>) -> StaticFiles {
+    StaticFiles {
+        root: dir.into(),
+        mount: "/".to_string(),
+        pattern: "/*".to_string(),
+        index: "index.html".to_string(),
+        spa_fallback: false,
+        precompressed: false,
+        cache_control: None,
+    }
+}
+
+struct Resolved {
+    path: PathBuf,
+    content_type: String,
+    encoding: Option<&'static str>,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    DateTime::parse_from_rfc2822(value).ok().map(SystemTime::from)
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/**
+ * parse a single `bytes=` range against a representation of `len` bytes,
+ * `Some(None)` means the range can not be satisfied, `None` that it should be ignored
+ **/
+fn parse_range(value: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let n: u64 = suffix.parse().ok()?;
+            if n == 0 {
+                return Some(None);
+            }
+            (len.saturating_sub(n), len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(len.saturating_sub(1))),
+    };
+    if start >= len || start > end {
+        return Some(None);
+    }
+    Some(Some((start, end)))
+}
+
+impl StaticFiles {
+    // Serve the directory under this path prefix instead of `/`.
+    pub fn mount(mut self: Self, prefix: &str) -> Self {
+        self.mount = prefix.trim_end_matches('/').to_string() + "/";
+        self.pattern = format!("{}*", self.mount);
+        self
+    }
+
+    pub fn index(mut self: Self, file: &str) -> Self {
+        self.index = file.to_string();
+        self
+    }
+
+    // Answer paths matching no file with the root index file.
+    pub fn spa_fallback(mut self: Self, enabled: bool) -> Self {
+        self.spa_fallback = enabled;
+        self
+    }
+
+    // Prefer `<file>.br` or `<file>.gz` when they exist and the client accepts them.
+    pub fn precompressed(mut self: Self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    pub fn cache_control(mut self: Self, value: &'static str) -> Self {
+        self.cache_control = Some(HeaderValue::from_static(value));
+        self
+    }
+
+    // Map a request path into the root, refusing anything escaping it.
+    fn map_path(self: &Self, path: &str) -> Option<PathBuf> {
+        let relative = path.strip_prefix(self.mount.as_str())
+            .or_else(|| (path.to_string() + "/" == self.mount).then_some(""))?;
+        let decoded = percent_decode(relative)?;
+        if decoded.contains('\0') || decoded.contains('\\') {
+            return None;
+        }
+        let mut out = self.root.clone();
+        for component in Path::new(&decoded).components() {
+            match component {
+                Component::Normal(c) => out.push(c),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        Some(out)
+    }
+
+    async fn resolve(self: &Self, path: &Path, accept_encoding: &str) -> Option<Resolved> {
+        let mut path = path.to_path_buf();
+        let mut meta = tokio::fs::metadata(&path).await.ok()?;
+        if meta.is_dir() {
+            path.push(&self.index);
+            meta = tokio::fs::metadata(&path).await.ok()?;
+        }
+        if !meta.is_file() {
+            return None;
+        }
+        let content_type = mime_guess::from_path(&path).first_or_octet_stream().to_string();
+        if self.precompressed {
+            let accept = AcceptEncoding::parse(accept_encoding);
+            let mut variants: Vec<(&'static str, &str, f32)> = [("br", "br"), ("gzip", "gz")].into_iter()
+                .map(|(encoding, ext)| (encoding, ext, accept.weight(encoding)))
+                .filter(|(_, _, q)| *q > 0.0)
+                .collect();
+            // highest q-value first, brotli on ties
+            variants.sort_by(|a, b| b.2.total_cmp(&a.2));
+            for (encoding, ext, _) in variants {
+                let mut name = path.clone().into_os_string();
+                name.push(".");
+                name.push(ext);
+                let variant = PathBuf::from(name);
+                if let Ok(m) = tokio::fs::metadata(&variant).await {
+                    if m.is_file() {
+                        return Some(Resolved {
+                            path: variant,
+                            content_type,
+                            encoding: Some(encoding),
+                            len: m.len(),
+                            modified: m.modified().ok(),
+                        });
+                    }
+                }
+            }
+        }
+        Some(Resolved {
+            path,
+            content_type,
+            encoding: None,
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+
+    fn etag(file: &Resolved) -> String {
+        let modified = file.modified.map(secs).unwrap_or(0);
+        match file.encoding {
+            Some(e) => format!("\"{:x}-{:x}-{}\"", file.len, modified, e),
+            None => format!("\"{:x}-{:x}\"", file.len, modified),
+        }
+    }
+
+    fn not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+        if let Some(inm) = headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+            return inm.split(',').any(|t| {
+                let t = t.trim();
+                t == "*" || t.trim_start_matches("W/") == etag
+            });
+        }
+        match (headers.get(header::IF_MODIFIED_SINCE).and_then(|v| v.to_str().ok()).and_then(parse_http_date), modified) {
+            (Some(since), Some(modified)) => secs(modified) <= secs(since),
+            _ => false,
+        }
+    }
+
+    async fn serve(self: &Self, file: Resolved, req: &HyperReq) -> std::io::Result<HyperResp> {
+        let etag = Self::etag(&file);
+        let mut builder = Response::builder()
+            .header(header::ETAG, &etag)
+            .header(header::ACCEPT_RANGES, "bytes");
+        if let Some(modified) = file.modified {
+            builder = builder.header(header::LAST_MODIFIED, http_date(modified));
+        }
+        if let Some(cc) = &self.cache_control {
+            builder = builder.header(header::CACHE_CONTROL, cc.clone());
+        }
+        if self.precompressed {
+            builder = builder.header(header::VARY, "Accept-Encoding");
+        }
+        if Self::not_modified(req.headers(), &etag, file.modified) {
+            return Ok(builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap());
+        }
+        builder = builder.header(header::CONTENT_TYPE, &file.content_type);
+        if let Some(encoding) = file.encoding {
+            builder = builder.header(header::CONTENT_ENCODING, encoding);
+        }
+
+        let if_range_ok = match req.headers().get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+            None => true,
+            Some(v) if v.starts_with('"') => v == etag,
+            Some(v) => matches!((parse_http_date(v), file.modified), (Some(d), Some(m)) if secs(m) <= secs(d)),
+        };
+        let range = req.headers().get(header::RANGE)
+            .and_then(|v| v.to_str().ok())
+            .filter(|_| if_range_ok)
+            .and_then(|v| parse_range(v, file.len));
+        let (status, start, end) = match range {
+            None => (StatusCode::OK, 0, file.len.saturating_sub(1)),
+            Some(Some((start, end))) => {
+                builder = builder.header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, file.len));
+                (StatusCode::PARTIAL_CONTENT, start, end)
+            }
+            Some(None) => {
+                return Ok(builder
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", file.len))
+                    .body(Body::empty())
+                    .unwrap());
+            }
+        };
+        let len = if file.len == 0 { 0 } else { end - start + 1 };
+        builder = builder.status(status).header(header::CONTENT_LENGTH, len);
+        if req.method() == Method::HEAD || len == 0 {
+            return Ok(builder.body(Body::empty()).unwrap());
+        }
+        let mut f = File::open(&file.path).await?;
+        f.seek(SeekFrom::Start(start)).await?;
+        let body = stream::unfold((f, len), |(mut f, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            let mut buf = vec![0u8; remaining.min(CHUNK) as usize];
+            match f.read_exact(&mut buf).await {
+                Ok(_) => Some((Ok::<_, std::io::Error>(buf), (f, remaining - CHUNK.min(remaining)))),
+                Err(e) => Some((Err(e), (f, 0))),
+            }
+        });
+        Ok(builder.body(Body::wrap_stream(body)).unwrap())
+    }
+}
+
+impl Select for StaticFiles {
+    fn method(self: &Self) -> Method {
+        Method::GET
+    }
+
+    fn methods(self: &Self) -> Vec<Method> {
+        vec![Method::GET, Method::HEAD]
+    }
+}
+
+impl Filter for StaticFiles {
+    fn test(self: &Self, path: &str) -> bool {
+        path.starts_with(self.mount.as_str()) || path.to_string() + "/" == self.mount
+    }
+    fn pattern(self: &Self) -> &str {
+        &self.pattern
+    }
+}
+
+#[async_trait::async_trait]
+impl Handler for StaticFiles {
+    async fn proc(self: &Self, path: String, body: HyperReq) -> hyper::Result<HyperResp> {
+        let target = match self.map_path(&path) {
+            Some(target) => target,
+            None => return Ok(Router::err_response(StatusCode::NOT_FOUND, "not found")),
+        };
+        let accept = body.headers().get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        let mut resolved = self.resolve(&target, &accept).await;
+        if resolved.is_none() && self.spa_fallback {
+            resolved = self.resolve(&self.root.join(&self.index), &accept).await;
+        }
+        match resolved {
+            None => Ok(Router::err_response(StatusCode::NOT_FOUND, "not found")),
+            Some(file) => match self.serve(file, &body).await {
+                Ok(res) => Ok(res),
+                Err(e) => Ok(Router::err_response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+            }
+        }
+    }
+}
+
+
+#[tokio::test]
+async fn test_static_files() {
+    let base = std::env::temp_dir().join(format!("static_files_{}", std::process::id()));
+    let dir = base.join("public");
+    let _ = std::fs::remove_dir_all(&base);
+    std::fs::create_dir_all(dir.join("assets")).unwrap();
+    std::fs::write(dir.join("index.html"), "<html>spa</html>").unwrap();
+    std::fs::write(dir.join("assets/app.js"), "console.log('0123456789');").unwrap();
+    std::fs::write(dir.join("assets/app.js.gz"), "gzipped").unwrap();
+    std::fs::write(base.join("secret.txt"), "secret").unwrap();
+
+    let mut r = Router::new();
+    r.add(static_files(&dir).mount("/admin").spa_fallback(true).precompressed(true));
+    let get = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+    async fn call(r: &Router, req: HyperReq) -> hyper::Result<HyperResp> {
+        let path = req.uri().path().to_string();
+        r.process(Method::GET, path, req).await
+    }
+    let text = |res: HyperResp| async { String::from_utf8(hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec()).unwrap() };
+
+    let res = call(&r, get("/admin/assets/app.js")).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "text/javascript");
+    let etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+    assert_eq!(text(res).await, "console.log('0123456789');");
+
+    let req = Request::get("/admin/assets/app.js").header(header::RANGE, "bytes=13-22").body(Body::empty()).unwrap();
+    let res = call(&r, req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 13-22/26");
+    assert_eq!(text(res).await, "0123456789");
+
+    let req = Request::get("/admin/assets/app.js").header(header::RANGE, "bytes=100-").body(Body::empty()).unwrap();
+    assert_eq!(call(&r, req).await.unwrap().status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+    let req = Request::get("/admin/assets/app.js").header(header::IF_NONE_MATCH, etag.as_str()).body(Body::empty()).unwrap();
+    assert_eq!(call(&r, req).await.unwrap().status(), StatusCode::NOT_MODIFIED);
+
+    let req = Request::get("/admin/assets/app.js").header(header::ACCEPT_ENCODING, "gzip, deflate").body(Body::empty()).unwrap();
+    let res = call(&r, req).await.unwrap();
+    assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "text/javascript");
+    assert_eq!(text(res).await, "gzipped");
+    for accept in ["gzip;q=0", "GZIP;q=0, deflate", "br"] {
+        let req = Request::get("/admin/assets/app.js").header(header::ACCEPT_ENCODING, accept).body(Body::empty()).unwrap();
+        let res = call(&r, req).await.unwrap();
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(text(res).await, "console.log('0123456789');");
+    }
+
+    let req = Request::head("/admin/assets/app.js").body(Body::empty()).unwrap();
+    let res = r.process(Method::HEAD, "/admin/assets/app.js".to_string(), req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::CONTENT_LENGTH], "26");
+    assert_eq!(text(res).await, "");
+
+    for path in ["/admin/", "/admin", "/admin/users/42"] {
+        let res = call(&r, get(path)).await.unwrap();
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(text(res).await, "<html>spa</html>");
+    }
+
+    let mut strict = Router::new();
+    strict.add(static_files(&dir).mount("/admin"));
+    for path in ["/admin/../secret.txt", "/admin/%2e%2e/secret.txt", "/admin/nope.txt"] {
+        assert_eq!(call(&strict, get(path)).await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+    let _ = std::fs::remove_dir_all(&base);
+}
@@ -0,0 +1,150 @@
+use std::fmt::Write;
+
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::{Body, Method, Request};
+use tracing::field::Empty;
+use tracing::Span;
+
+type HyperReq = Request<Body>;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+/**
+ * W3C trace context of a request. `parent_id` is the span id received in
+ * `traceparent`, `span_id` is the one generated for the span handling the request.
+ * stored in the request extensions by HttpServer.
+ **/
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub parent_id: Option<[u8; 8]>,
+    pub span_id: [u8; 8],
+    pub flags: u8,
+    pub state: Option<String>,
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+pub(crate) fn unhex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut out = [0u8; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+impl TraceContext {
+    // Start a new trace, used when the request carries no valid `traceparent`.
+    pub fn root() -> Self {
+        TraceContext {
+            trace_id: rand::random(),
+            parent_id: None,
+            span_id: rand::random(),
+            flags: 1,
+            state: None,
+        }
+    }
+
+    /**
+     * parse `00-<trace-id>-<parent-id>-<flags>`, all-zero ids are invalid.
+     * versions above 00 are accepted as long as the first four fields parse.
+     **/
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = unhex::<1>(parts.next()?)?;
+        let trace_id = unhex::<16>(parts.next()?)?;
+        let parent_id = unhex::<8>(parts.next()?)?;
+        let flags = unhex::<1>(parts.next()?)?;
+        if version[0] == 0xff || (version[0] == 0 && parts.next().is_some())
+            || trace_id == [0; 16] || parent_id == [0; 8] {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id,
+            parent_id: Some(parent_id),
+            span_id: rand::random(),
+            flags: flags[0],
+            state: tracestate.map(|s| s.to_string()),
+        })
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let value = |name| headers.get(name).and_then(|v: &HeaderValue| v.to_str().ok());
+        value(TRACEPARENT)
+            .and_then(|tp| Self::parse(tp, value(TRACESTATE)))
+            .unwrap_or_else(Self::root)
+    }
+
+    pub fn trace_id(self: &Self) -> String {
+        hex(&self.trace_id)
+    }
+
+    pub fn span_id(self: &Self) -> String {
+        hex(&self.span_id)
+    }
+
+    // `traceparent` naming the span of this request as parent, for responses and outgoing calls.
+    pub fn traceparent(self: &Self) -> String {
+        format!("00-{}-{}-{:02x}", hex(&self.trace_id), hex(&self.span_id), self.flags)
+    }
+
+    pub fn inject(self: &Self, headers: &mut HeaderMap) {
+        headers.insert(TRACEPARENT, HeaderValue::from_str(&self.traceparent()).unwrap());
+        if let Some(state) = self.state.as_ref().and_then(|s| HeaderValue::from_str(s).ok()) {
+            headers.insert(TRACESTATE, state);
+        }
+    }
+
+    pub(crate) fn request_span(self: &Self, method: &Method, path: &str) -> Span {
+        tracing::info_span!(
+            "request",
+            method = %method,
+            path = path,
+            route = Empty,
+            status = Empty,
+            request_id = Empty,
+            trace_id = %self.trace_id(),
+            span_id = %self.span_id(),
+            parent_id = %self.parent_id.map(|p| hex(&p)).unwrap_or_default(),
+        )
+    }
+}
+
+// Read the trace context of a request, or the one of a fresh trace.
+pub fn context(req: &HyperReq) -> TraceContext {
+    req.extensions().get::<TraceContext>().cloned()
+        .unwrap_or_else(|| TraceContext::from_headers(req.headers()))
+}
+
+
+#[test]
+fn test_trace_context() {
+    let tp = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let ctx = TraceContext::parse(tp, Some("congo=t61rcWkgMzE")).unwrap();
+    assert_eq!(ctx.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(ctx.parent_id.map(|p| hex(&p)).unwrap(), "00f067aa0ba902b7");
+    assert_ne!(ctx.span_id, ctx.parent_id.unwrap());
+    assert!(ctx.traceparent().starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+    assert!(ctx.traceparent().ends_with("-01"));
+
+    assert!(TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01", None).is_none());
+    assert!(TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7", None).is_none());
+    assert!(TraceContext::parse("zz-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", None).is_none());
+    assert!(TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-future", None).is_some());
+
+    let mut headers = HeaderMap::new();
+    ctx.inject(&mut headers);
+    let echoed = TraceContext::from_headers(&headers);
+    assert_eq!(echoed.trace_id, ctx.trace_id);
+    assert_eq!(echoed.parent_id, Some(ctx.span_id));
+    assert_eq!(echoed.state.as_deref(), Some("congo=t61rcWkgMzE"));
+}
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+
+use regex::Regex;
+use serde::Serialize;
+
+/**
+ * types checked by the `validate` stage, usually implemented with the
+ * `validate!` macro listing the rules of each field.
+ **/
+pub trait Validate {
+    fn validate(self: &Self) -> Result<(), ValidationErrors>;
+}
+
+// One broken rule, `field` is a path like `address.city` or `tags[2]`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+// Every rule broken by a value, answered with a 422 listing them as JSON.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn add(self: &mut Self, field: &str, code: &'static str, message: String) {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            code,
+            message,
+        });
+    }
+
+    // Errors of a nested value, their fields prefixed with `field`.
+    pub fn nest(self: &mut Self, field: &str, nested: ValidationErrors) {
+        for mut e in nested.errors {
+            e.field = if e.field.is_empty() {
+                field.to_string()
+            } else if e.field.starts_with('[') {
+                format!("{}{}", field, e.field)
+            } else {
+                format!("{}.{}", field, e.field)
+            };
+            self.errors.push(e);
+        }
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn into_result(self: Self) -> Result<(), ValidationErrors> {
+        if self.is_empty() { Ok(()) } else { Err(self) }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields: Vec<String> = self.errors.iter().map(|e| format!("{}: {}", e.field, e.message)).collect();
+        write!(f, "validation failed: {}", fields.join(", "))
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+// A check of a field value, adding what it breaks to `errors`.
+pub trait Rule<T: ?Sized> {
+    fn check(self: &Self, value: &T, field: &str, errors: &mut ValidationErrors);
+}
+
+// Values whose length is checked: chars of a string, items of a collection.
+pub trait HasLength {
+    fn length(self: &Self) -> usize;
+}
+
+impl HasLength for str {
+    fn length(self: &Self) -> usize {
+        self.chars().count()
+    }
+}
+
+impl HasLength for String {
+    fn length(self: &Self) -> usize {
+        self.chars().count()
+    }
+}
+
+impl<T> HasLength for Vec<T> {
+    fn length(self: &Self) -> usize {
+        self.len()
+    }
+}
+
+impl<T> HasLength for [T] {
+    fn length(self: &Self) -> usize {
+        self.len()
+    }
+}
+
+impl<K, V, S> HasLength for HashMap<K, V, S> {
+    fn length(self: &Self) -> usize {
+        self.len()
+    }
+}
+
+pub struct Length {
+    min: usize,
+    max: usize,
+}
+
+// Between `min` and `max` chars or items, both included.
+pub fn length(min: usize, max: usize) -> Length {
+    Length { min, max }
+}
+
+impl<T: HasLength + ?Sized> Rule<T> for Length {
+    fn check(self: &Self, value: &T, field: &str, errors: &mut ValidationErrors) {
+        let len = value.length();
+        if len < self.min || len > self.max {
+            errors.add(field, "length", format!("length must be between {} and {}, got {}", self.min, self.max, len));
+        }
+    }
+}
+
+pub struct Range<N> {
+    min: N,
+    max: N,
+}
+
+// Between `min` and `max`, both included.
+pub fn range<N>(min: N, max: N) -> Range<N> {
+    Range { min, max }
+}
+
+impl<N: PartialOrd + fmt::Display> Rule<N> for Range<N> {
+    fn check(self: &Self, value: &N, field: &str, errors: &mut ValidationErrors) {
+        if *value < self.min || *value > self.max {
+            errors.add(field, "range", format!("must be between {} and {}, got {}", self.min, self.max, value));
+        }
+    }
+}
+
+pub struct Email;
+
+// Something like `local@domain.tld`, no attempt at the full RFC 5322 grammar.
+pub fn email() -> Email {
+    Email
+}
+
+impl<T: AsRef<str> + ?Sized> Rule<T> for Email {
+    fn check(self: &Self, value: &T, field: &str, errors: &mut ValidationErrors) {
+        let valid = match value.as_ref().split_once('@') {
+            Some((local, domain)) => !local.is_empty() && !domain.contains('@') && !domain.contains(char::is_whitespace)
+                && domain.split('.').count() > 1 && domain.split('.').all(|label| !label.is_empty()),
+            None => false,
+        };
+        if !valid {
+            errors.add(field, "email", "must be an email address".to_string());
+        }
+    }
+}
+
+pub struct Pattern(&'static str);
+
+// Matching the regex, compiled once per pattern.
+pub fn pattern(regex: &'static str) -> Pattern {
+    Pattern(regex)
+}
+
+impl<T: AsRef<str> + ?Sized> Rule<T> for Pattern {
+    fn check(self: &Self, value: &T, field: &str, errors: &mut ValidationErrors) {
+        static COMPILED: OnceLock<Mutex<HashMap<&'static str, Regex>>> = OnceLock::new();
+        let mut compiled = COMPILED.get_or_init(Default::default).lock().unwrap();
+        let regex = compiled.entry(self.0).or_insert_with(|| Regex::new(self.0).expect("invalid validation pattern"));
+        if !regex.is_match(value.as_ref()) {
+            errors.add(field, "pattern", format!("must match {}", self.0));
+        }
+    }
+}
+
+pub struct Required;
+
+// Present, for Option fields.
+pub fn required() -> Required {
+    Required
+}
+
+impl<T> Rule<Option<T>> for Required {
+    fn check(self: &Self, value: &Option<T>, field: &str, errors: &mut ValidationErrors) {
+        if value.is_none() {
+            errors.add(field, "required", "is required".to_string());
+        }
+    }
+}
+
+pub struct Optional<R>(R);
+
+// Checks an Option field with `rule` when it is present.
+pub fn optional<R>(rule: R) -> Optional<R> {
+    Optional(rule)
+}
+
+impl<T, R: Rule<T>> Rule<Option<T>> for Optional<R> {
+    fn check(self: &Self, value: &Option<T>, field: &str, errors: &mut ValidationErrors) {
+        if let Some(value) = value {
+            self.0.check(value, field, errors);
+        }
+    }
+}
+
+pub struct Each<R>(R);
+
+// Checks every item of a collection with `rule`, fields are `field[i]`.
+pub fn each<R>(rule: R) -> Each<R> {
+    Each(rule)
+}
+
+impl<T, R: Rule<T>> Rule<Vec<T>> for Each<R> {
+    fn check(self: &Self, value: &Vec<T>, field: &str, errors: &mut ValidationErrors) {
+        for (i, item) in value.iter().enumerate() {
+            self.0.check(item, &format!("{}[{}]", field, i), errors);
+        }
+    }
+}
+
+pub struct Nested;
+
+// Runs the Validate of a field holding a struct.
+pub fn nested() -> Nested {
+    Nested
+}
+
+impl<T: Validate> Rule<T> for Nested {
+    fn check(self: &Self, value: &T, field: &str, errors: &mut ValidationErrors) {
+        if let Err(nested) = value.validate() {
+            errors.nest(field, nested);
+        }
+    }
+}
+
+pub struct Custom<F>(&'static str, F);
+
+// `check` gives the message when the value is invalid, reported with `code`.
+pub fn custom<T: ?Sized, F>(code: &'static str, check: F) -> Custom<F>
+    where F: Fn(&T) -> Result<(), String> {
+    Custom(code, check)
+}
+
+impl<T: ?Sized, F> Rule<T> for Custom<F> where F: Fn(&T) -> Result<(), String> {
+    fn check(self: &Self, value: &T, field: &str, errors: &mut ValidationErrors) {
+        if let Err(message) = (self.1)(value) {
+            errors.add(field, self.0, message);
+        }
+    }
+}
+
+/**
+ * implements Validate for a struct from the rules of its fields:
+ *
+ * validate!(NewUser {
+ *     name: [length(1, 50)],
+ *     email: [email()],
+ *     age: [optional(range(13, 150))],
+ *     address: [nested()],
+ *     tags: [length(0, 5), each(length(1, 20))],
+ * });
+ *
+ * with the rules of `hyper_restful_rs::http::validate` in scope.
+ * every rule of every field is checked, so all the errors are reported.
+ **/
+#[macro_export]
+macro_rules! validate {
+    ($ty:ty { $($field:ident: [$($rule:expr),* $(,)?]),* $(,)? }) => {
+        impl $crate::http::validate::Validate for $ty {
+            fn validate(self: &Self) -> Result<(), $crate::http::validate::ValidationErrors> {
+                let mut errors = $crate::http::validate::ValidationErrors::default();
+                $($($crate::http::validate::Rule::check(&$rule, &self.$field, stringify!($field), &mut errors);)*)*
+                errors.into_result()
+            }
+        }
+    };
+}
+
+
+#[test]
+fn test_rules() {
+    #[derive(serde::Deserialize)]
+    struct Address {
+        city: String,
+        zip: String,
+    }
+    validate!(Address {
+        city: [length(1, 20)],
+        zip: [pattern("^[0-9]{5}$")],
+    });
+    #[derive(serde::Deserialize)]
+    struct NewUser {
+        name: String,
+        email: String,
+        age: Option<u32>,
+        address: Address,
+        tags: Vec<String>,
+        previous: Vec<Address>,
+        nickname: Option<String>,
+    }
+    validate!(NewUser {
+        name: [length(1, 10), custom("reserved", |n: &String| if n == "admin" { Err("is reserved".to_string()) } else { Ok(()) })],
+        email: [email()],
+        age: [optional(range(13, 150))],
+        address: [nested()],
+        tags: [length(0, 2), each(length(1, 5))],
+        previous: [each(nested())],
+        nickname: [required()],
+    });
+
+    let user: NewUser = serde_json::from_value(serde_json::json!({
+        "name": "ann", "email": "ann@example.org", "age": 30, "nickname": "a",
+        "address": {"city": "Paris", "zip": "75001"}, "tags": ["a"], "previous": [],
+    })).unwrap();
+    assert!(user.validate().is_ok());
+
+    let user: NewUser = serde_json::from_value(serde_json::json!({
+        "name": "admin", "email": "admin@localhost", "age": 7,
+        "address": {"city": "", "zip": "7500"}, "tags": ["a", "toolong", "b"],
+        "previous": [{"city": "Lyon", "zip": "69001"}, {"city": "Lyon", "zip": "x"}],
+    })).unwrap();
+    let errors = user.validate().err().unwrap();
+    let fields: Vec<(&str, &str)> = errors.errors.iter().map(|e| (e.field.as_str(), e.code)).collect();
+    assert_eq!(fields, vec![
+        ("name", "reserved"),
+        ("email", "email"),
+        ("age", "range"),
+        ("address.city", "length"),
+        ("address.zip", "pattern"),
+        ("tags", "length"),
+        ("tags[1]", "length"),
+        ("previous[1].zip", "pattern"),
+        ("nickname", "required"),
+    ]);
+}
+
+
+#[tokio::test]
+async fn test_validate_stage() {
+    use hyper::{Body, Method, Request, StatusCode};
+    use serde::{Deserialize, Serialize};
+    use crate::http::handler::{Filter, POST};
+    use crate::http::router::Router;
+
+    #[derive(Serialize, Deserialize)]
+    struct Signup {
+        name: String,
+        email: String,
+    }
+    validate!(Signup {
+        name: [length(1, 10)],
+        email: [email()],
+    });
+    let mut r = Router::new();
+    r.add(POST().eq("/signup").handle_request().parse_json::<Signup>().validate().then(|s| s.name).ok());
+
+    let post = |body: &'static str| Request::post("/signup").body(Body::from(body)).unwrap();
+    let res = r.process(Method::POST, "/signup".to_string(), post(r#"{"name": "ann", "email": "ann@example.org"}"#)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = r.process(Method::POST, "/signup".to_string(), post(r#"{"name": "", "email": "nope"}"#)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(res.headers()[hyper::header::CONTENT_TYPE], "application/json");
+    let body: serde_json::Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["message"], "validation failed");
+    assert_eq!(body["errors"], serde_json::json!([
+        {"field": "name", "code": "length", "message": "length must be between 1 and 10, got 0"},
+        {"field": "email", "code": "email", "message": "must be an email address"},
+    ]));
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::needless_arbitrary_self_type)]
+
 pub mod http;
 pub mod pipeline;
-mod log;
\ No newline at end of file
+pub mod log;
\ No newline at end of file
@@ -1,27 +1,533 @@
-use chrono::Local;
-use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-struct SimpleLogger;
+use chrono::{Local, SecondsFormat, Utc};
+use log::{LevelFilter, Metadata, Record, SetLoggerError};
+use serde::Serialize;
 
-#[allow(dead_code)]
-static LOGGER: SimpleLogger = SimpleLogger;
+use crate::http::request_id;
 
-#[allow(dead_code)]
-pub fn init() -> Result<(), SetLoggerError> {
-    log::set_logger(&LOGGER)
-        .map(|()| log::set_max_level(LevelFilter::Info))
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy)]
+pub enum Timezone {
+    Local,
+    Utc,
+}
+
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+pub enum Output {
+    Stdout,
+    Stderr,
+    File(FileOutput),
+}
+
+/**
+ * file output, rotated when it grows over `max_size` bytes or when the
+ * rotation period changes. size rotation keeps `path.1` .. `path.<keep>`,
+ * period rotation renames the file with the period it covered as suffix
+ * and keeps the `keep` latest periods.
+ **/
+pub struct FileOutput {
+    path: PathBuf,
+    max_size: Option<u64>,
+    keep: usize,
+    rotation: Rotation,
+}
+
+impl FileOutput {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileOutput {
+            path: path.into(),
+            max_size: None,
+            keep: 5,
+            rotation: Rotation::Never,
+        }
+    }
+
+    pub fn max_size(mut self: Self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    pub fn keep(mut self: Self, files: usize) -> Self {
+        self.keep = files;
+        self
+    }
+
+    pub fn rotation(mut self: Self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+}
+
+pub struct Builder {
+    env: String,
+    level: LevelFilter,
+    format: Format,
+    timezone: Timezone,
+    output: Output,
+    capacity: usize,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder {
+            env: "RUST_LOG".to_string(),
+            level: LevelFilter::Info,
+            format: Format::Text,
+            timezone: Timezone::Local,
+            output: Output::Stderr,
+            capacity: 4096,
+        }
+    }
+
+    /**
+     * env var holding level directives like `info,hyper=warn,my_app::db=debug`,
+     * the longest matching target prefix wins
+     **/
+    pub fn env(mut self: Self, name: &str) -> Self {
+        self.env = name.to_string();
+        self
+    }
+
+    // Level used when neither the env var nor a directive covers a target.
+    pub fn level(mut self: Self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn format(mut self: Self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn timezone(mut self: Self, timezone: Timezone) -> Self {
+        self.timezone = timezone;
+        self
+    }
+
+    pub fn output(mut self: Self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    // Lines queued for the writer thread before new ones get dropped.
+    pub fn capacity(mut self: Self, lines: usize) -> Self {
+        self.capacity = lines;
+        self
+    }
+
+    pub fn build(self: Self) -> io::Result<SimpleLogger> {
+        let directives = env::var(&self.env).ok()
+            .map(|v| parse_directives(&v, self.level))
+            .unwrap_or_else(|| Directives { default: self.level, targets: Vec::new() });
+        let sink = Sink::open(self.output, self.timezone)?;
+        let (tx, rx) = sync_channel(self.capacity);
+        thread::Builder::new()
+            .name("simple-logger".to_string())
+            .spawn(move || sink.run(rx))?;
+        Ok(SimpleLogger {
+            directives,
+            format: self.format,
+            timezone: self.timezone,
+            tx,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /**
+     * install the logger. lines still queued when the process exits are lost,
+     * call `log::logger().flush()` before exiting, HttpServer::stop does.
+     **/
+    pub fn init(self: Self) -> Result<(), InitError> {
+        let logger = self.build().map_err(InitError::Output)?;
+        let max = logger.directives.max();
+        log::set_logger(Box::leak(Box::new(logger)))
+            .map(|()| log::set_max_level(max))
+            .map_err(InitError::AlreadySet)
+    }
+}
+
+#[derive(Debug)]
+pub enum InitError {
+    // the output could not be opened
+    Output(io::Error),
+    AlreadySet(SetLoggerError),
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::Output(e) => write!(f, "can not open log output: {}", e),
+            InitError::AlreadySet(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for InitError {}
+
+struct Directives {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Directives {
+    fn level(self: &Self, target: &str) -> LevelFilter {
+        self.targets.iter()
+            .filter(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    fn max(self: &Self) -> LevelFilter {
+        self.targets.iter().map(|(_, l)| *l).fold(self.default, |a, b| a.max(b))
+    }
+}
+
+fn parse_directives(spec: &str, default: LevelFilter) -> Directives {
+    let mut directives = Directives { default, targets: Vec::new() };
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match part.split_once('=') {
+            None => match part.parse() {
+                Ok(level) => directives.default = level,
+                // a bare module name turns everything on for it
+                Err(_) => directives.targets.push((part.to_string(), LevelFilter::Trace)),
+            },
+            Some((target, level)) => {
+                if let Ok(level) = level.trim().parse() {
+                    directives.targets.push((target.trim().to_string(), level));
+                }
+            }
+        }
+    }
+    directives
+}
+
+enum Message {
+    Line(String),
+    Flush(SyncSender<()>),
+}
+
+enum Sink {
+    Stdout(BufWriter<io::Stdout>),
+    Stderr(BufWriter<io::Stderr>),
+    File(RotatingFile),
+}
+
+impl Sink {
+    fn open(output: Output, timezone: Timezone) -> io::Result<Self> {
+        Ok(match output {
+            Output::Stdout => Sink::Stdout(BufWriter::new(io::stdout())),
+            Output::Stderr => Sink::Stderr(BufWriter::new(io::stderr())),
+            Output::File(f) => Sink::File(RotatingFile::open(f, timezone)?),
+        })
+    }
+
+    fn write(self: &mut Self, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stdout(w) => writeln!(w, "{}", line),
+            Sink::Stderr(w) => writeln!(w, "{}", line),
+            Sink::File(f) => f.write(line),
+        }
+    }
+
+    fn flush(self: &mut Self) -> io::Result<()> {
+        match self {
+            Sink::Stdout(w) => w.flush(),
+            Sink::Stderr(w) => w.flush(),
+            Sink::File(f) => f.writer.flush(),
+        }
+    }
+
+    // Drain whatever is queued before flushing, so a burst costs one flush.
+    fn run(mut self: Self, rx: Receiver<Message>) {
+        while let Ok(msg) = rx.recv() {
+            let mut acks = Vec::new();
+            let mut next = Some(msg);
+            while let Some(msg) = next {
+                match msg {
+                    Message::Line(line) => {
+                        let _ = self.write(&line);
+                    }
+                    Message::Flush(ack) => acks.push(ack),
+                }
+                next = rx.try_recv().ok();
+            }
+            let _ = self.flush();
+            for ack in acks {
+                let _ = ack.send(());
+            }
+        }
+    }
+}
+
+struct RotatingFile {
+    config: FileOutput,
+    timezone: Timezone,
+    writer: BufWriter<File>,
+    size: u64,
+    period: String,
+}
+
+impl RotatingFile {
+    fn open(config: FileOutput, timezone: Timezone) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        let period = Self::period(&config.rotation, timezone);
+        Ok(RotatingFile {
+            config,
+            timezone,
+            writer: BufWriter::new(file),
+            size,
+            period,
+        })
+    }
+
+    // the period is taken in the configured timezone, like the line timestamps
+    fn period(rotation: &Rotation, timezone: Timezone) -> String {
+        let format = match rotation {
+            Rotation::Never => return String::new(),
+            Rotation::Hourly => "%Y-%m-%d-%H",
+            Rotation::Daily => "%Y-%m-%d",
+        };
+        match timezone {
+            Timezone::Local => Local::now().format(format).to_string(),
+            Timezone::Utc => Utc::now().format(format).to_string(),
+        }
+    }
+
+    fn suffixed(self: &Self, suffix: &str) -> PathBuf {
+        let mut name = self.config.path.clone().into_os_string();
+        name.push(".");
+        name.push(suffix);
+        PathBuf::from(name)
+    }
+
+    fn write(self: &mut Self, line: &str) -> io::Result<()> {
+        let period = Self::period(&self.config.rotation, self.timezone);
+        if period != self.period {
+            let old = std::mem::replace(&mut self.period, period);
+            self.rotate(|f| {
+                fs::rename(&f.config.path, f.suffixed(&old))?;
+                f.prune_periods()
+            })?;
+        } else if self.config.max_size.is_some_and(|max| self.size > 0 && self.size + line.len() as u64 + 1 > max) {
+            self.rotate(|f| {
+                for i in (1..f.config.keep).rev() {
+                    let _ = fs::rename(f.suffixed(&i.to_string()), f.suffixed(&(i + 1).to_string()));
+                }
+                if f.config.keep > 0 {
+                    fs::rename(&f.config.path, f.suffixed("1"))
+                } else {
+                    fs::remove_file(&f.config.path)
+                }
+            })?;
+        }
+        writeln!(self.writer, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // removes the files of the oldest periods, leaving `keep` of them
+    fn prune_periods(self: &Self) -> io::Result<()> {
+        let (Some(dir), Some(name)) = (self.config.path.parent(), self.config.path.file_name()) else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() { std::path::Path::new(".") } else { dir };
+        let prefix = format!("{}.", name.to_string_lossy());
+        let mut periods: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // periods are dates like `2024-05-01`, size rotations are numbers
+                name.strip_prefix(&prefix).is_some_and(|suffix| suffix.contains('-') && suffix.chars().all(|c| c.is_ascii_digit() || c == '-'))
+            })
+            .map(|entry| entry.path())
+            .collect();
+        // dates sort in time order
+        periods.sort();
+        let stale = periods.len().saturating_sub(self.config.keep);
+        for path in &periods[..stale] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn rotate(self: &mut Self, f: impl FnOnce(&Self) -> io::Result<()>) -> io::Result<()> {
+        self.writer.flush()?;
+        f(self)?;
+        let file = OpenOptions::new().create(true).append(true).open(&self.config.path)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    level: &'a str,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    message: String,
+}
+
+/**
+ * logger handing formatted lines to a writer thread over a bounded queue,
+ * so logging never blocks the caller. lines are dropped when the queue is full.
+ **/
+pub struct SimpleLogger {
+    directives: Directives,
+    format: Format,
+    timezone: Timezone,
+    tx: SyncSender<Message>,
+    dropped: AtomicU64,
+}
+
+impl SimpleLogger {
+    // Number of lines lost because the writer thread could not keep up.
+    pub fn dropped(self: &Self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn time(self: &Self) -> String {
+        match self.timezone {
+            Timezone::Local => Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+            Timezone::Utc => Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+
+    fn format(self: &Self, record: &Record) -> String {
+        let request_id = request_id::current();
+        match self.format {
+            Format::Text => match request_id {
+                Some(id) => format!("{} {} [{}] - {}", self.time(), record.level(), id, record.args()),
+                None => format!("{} {} - {}", self.time(), record.level(), record.args()),
+            },
+            Format::Json => serde_json::to_string(&JsonLine {
+                time: self.time(),
+                level: record.level().as_str(),
+                target: record.target(),
+                request_id,
+                message: record.args().to_string(),
+            }).unwrap(),
+        }
+    }
 }
 
 impl log::Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+        metadata.level() <= self.directives.level(metadata.target())
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            println!("{} {} - {}", Local::now(), record.level(), record.args());
+            let line = self.format(record);
+            if let Err(TrySendError::Full(_)) = self.tx.try_send(Message::Line(line)) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
         }
     }
 
-    fn flush(&self) {}
+    // waits for the queued lines to be written, at most a second so a stuck output can't hang an exit.
+    // the deadline covers queueing the flush too, the queue stays full while the output is stuck.
+    fn flush(&self) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let (ack, done) = sync_channel(1);
+        let mut msg = Message::Flush(ack);
+        loop {
+            match self.tx.try_send(msg) {
+                Ok(()) => break,
+                Err(TrySendError::Full(m)) if Instant::now() < deadline => {
+                    msg = m;
+                    thread::sleep(Duration::from_millis(5));
+                }
+                Err(_) => return,
+            }
+        }
+        let _ = done.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+// Install the default logger: `RUST_LOG` directives, info otherwise, text lines on stderr.
+pub fn init() -> Result<(), InitError> {
+    Builder::new().init()
+}
+
+
+#[test]
+fn test_simple_logger() {
+    use log::{Level, Log};
+    let directives = parse_directives("warn,hyper=error,my_app::db=debug,my_app", LevelFilter::Info);
+    assert_eq!(directives.level("other"), LevelFilter::Warn);
+    assert_eq!(directives.level("hyper::proto"), LevelFilter::Error);
+    assert_eq!(directives.level("my_app::db::pool"), LevelFilter::Debug);
+    assert_eq!(directives.level("my_app::http"), LevelFilter::Trace);
+    assert_eq!(directives.max(), LevelFilter::Trace);
+
+    let dir = env::temp_dir().join(format!("simple_logger_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("app.log");
+    let logger = Builder::new()
+        .env("SIMPLE_LOGGER_TEST_UNSET")
+        .format(Format::Json)
+        .timezone(Timezone::Utc)
+        .output(Output::File(FileOutput::new(&path).max_size(200).keep(2)))
+        .build()
+        .unwrap();
+    for i in 0..10 {
+        logger.log(&Record::builder()
+            .args(format_args!("line {}", i))
+            .level(Level::Info)
+            .target("test")
+            .build());
+    }
+    logger.log(&Record::builder().args(format_args!("hidden")).level(Level::Debug).target("test").build());
+    logger.flush();
+    let current = fs::read_to_string(&path).unwrap();
+    let last: serde_json::Value = serde_json::from_str(current.lines().last().unwrap()).unwrap();
+    assert_eq!(last["message"], "line 9");
+    assert!(last["time"].as_str().unwrap().ends_with('Z'));
+    assert!(dir.join("app.log.1").exists());
+    assert!(dir.join("app.log.2").exists());
+    assert!(!dir.join("app.log.3").exists());
+    assert!(!current.contains("hidden"));
+
+    let daily = RotatingFile::open(FileOutput::new(dir.join("daily.log")).rotation(Rotation::Daily).keep(2), Timezone::Utc).unwrap();
+    assert_eq!(daily.period, Utc::now().format("%Y-%m-%d").to_string());
+    for suffix in ["2024-01-01", "2024-01-02", "2024-01-03", "1"] {
+        fs::write(dir.join(format!("daily.log.{}", suffix)), "").unwrap();
+    }
+    daily.prune_periods().unwrap();
+    assert!(!dir.join("daily.log.2024-01-01").exists());
+    assert!(dir.join("daily.log.2024-01-02").exists());
+    assert!(dir.join("daily.log.2024-01-03").exists());
+    assert!(dir.join("daily.log.1").exists());
+    assert!(matches!(Builder::new().output(Output::File(FileOutput::new(dir.join("missing/app.log")))).init(), Err(InitError::Output(_))));
+    let _ = fs::remove_dir_all(&dir);
 }
@@ -2,6 +2,14 @@ pub mod link;
 pub mod test;
 pub mod connect;
 pub mod async_connect;
+pub mod boxed;
+pub mod branch;
+pub mod cache;
+pub mod parallel;
+pub mod pipe;
+pub mod recover;
+pub mod resilience;
+pub mod stage;
 
 // use std::future::Future;
 // use std::marker::PhantomData;
@@ -1,64 +1,74 @@
 use std::future::Future;
-use crate::pipeline::link::{Error, ErrorFuc, Linkable, Pipeline, Start};
+use tracing::Instrument;
+use crate::pipeline::link::{stage_span, Error, ErrorFuc, Linkable, Pipeline, Start};
+use crate::pipeline::stage::{append, Stage, StageError};
 
 pub struct AsyncConnect<P, F> {
     pub(crate) prev: P,
     pub(crate) next: F,
+    pub(crate) name: Option<&'static str>,
 }
 
 impl<NXT, Fut, F, L> Linkable for AsyncConnect<L, F>
     where F: 'static + Fn(L::OUT) -> Fut + Send + Sync,
-          Fut: 'static + Future<Output=NXT> + Send + Sync,
+          Fut: 'static + Future<Output=NXT> + Send,
           NXT: Send + Sync,
           L: Linkable + Send + Sync {
     type OUT = NXT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("then_async", self.name))
+    }
 }
 
 #[async_trait::async_trait]
 impl<NXT, Fut, F, P> Pipeline for AsyncConnect<P, F>
     where P: Pipeline + Sync + Send,
           F: Fn(P::OUT) -> Fut + 'static + Send + Sync,
-          Fut: Future<Output=NXT> + Send + Sync + 'static,
+          Fut: Future<Output=NXT> + Send + 'static,
           NXT: Send + Sync
 {
     type IN = P::IN;
     async fn process(self: &Self, input: Self::IN) -> Result<NXT, Error> {
         let out = self.prev.process(input).await?;
-        Ok((self.next)(out).await)
+        Ok((self.next)(out).instrument(stage_span(self.name)).await)
     }
 }
 
 #[async_trait::async_trait]
 impl<NXT, Fut, F, IN> Pipeline for AsyncConnect<Start<IN>, F>
     where F: Fn(IN) -> Fut + 'static + Send + Sync,
-          Fut: Future<Output=NXT> + Send + Sync + 'static,
+          Fut: Future<Output=NXT> + Send + 'static,
           NXT: Send + Sync,
           IN: Send + Sync
 {
     type IN = IN;
     async fn process(self: &Self, input: IN) -> Result<NXT, Error> {
-        Ok((self.next)(input).await)
+        Ok((self.next)(input).instrument(stage_span(self.name)).await)
     }
 }
 
 impl<NXT, Fut, F, L> Linkable for AsyncConnect<L, ErrorFuc<F>>
     where F: Fn(L::OUT) -> Fut + Send + Sync,
-          Fut: Future<Output=Result<NXT, Error>> + Send + Sync + 'static,
+          Fut: Future<Output=Result<NXT, Error>> + Send + 'static,
           NXT: Send + Sync,
           L: Linkable + Send + Sync {
     type OUT = NXT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("then_async_result", self.name))
+    }
 }
 
 #[async_trait::async_trait]
 impl<NXT, Fut, F, P> Pipeline for AsyncConnect<P, ErrorFuc<F>>
     where F: Fn(P::OUT) -> Fut + Send + Sync,
-          Fut: Future<Output=Result<NXT, Error>> + Send + Sync + 'static,
+          Fut: Future<Output=Result<NXT, Error>> + Send + 'static,
           NXT: Send + Sync,
           P: Pipeline + Send + Sync {
     type IN = P::IN;
     async fn process(self: &Self, input: Self::IN) -> Result<NXT, Error> {
         let out = self.prev.process(input).await?;
-        (self.next.f)(out).await
+        (self.next.f)(out).instrument(stage_span(self.name)).await
+            .map_err(|e| StageError::wrap(e, self.prev.stages().len(), self.name))
     }
 }
 
@@ -66,11 +76,12 @@ impl<NXT, Fut, F, P> Pipeline for AsyncConnect<P, ErrorFuc<F>>
 impl<NXT, Fut, F, IN> Pipeline for AsyncConnect<Start<IN>, ErrorFuc<F>>
     where IN: Sync + Send,
           F: Fn(IN) -> Fut + Sync + Send,
-          Fut: Future<Output=Result<NXT, Error>> + Send + Sync + 'static,
+          Fut: Future<Output=Result<NXT, Error>> + Send + 'static,
           NXT: Send + Sync
 {
     type IN = IN;
     async fn process(self: &Self, input: Self::IN) -> Result<NXT, Error> {
-        (self.next.f)(input).await
+        (self.next.f)(input).instrument(stage_span(self.name)).await
+            .map_err(|e| StageError::wrap(e, 0, self.name))
     }
 }
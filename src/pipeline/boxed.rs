@@ -0,0 +1,96 @@
+use crate::pipeline::link::{Error, Linkable, Pipeline};
+use crate::pipeline::stage::Stage;
+
+/**
+ * pipeline behind a pointer, with a type naming only its input and output,
+ * to store it in a field or return it from a function. costs one allocation
+ * when boxed and one dynamic call per process.
+ **/
+pub struct BoxedPipeline<IN, OUT> {
+    inner: Box<dyn Pipeline<IN=IN, OUT=OUT> + Send + Sync>,
+}
+
+impl<IN, OUT> BoxedPipeline<IN, OUT> {
+    pub fn new<P>(pipeline: P) -> Self where P: Pipeline<IN=IN, OUT=OUT> + Send + Sync + 'static {
+        BoxedPipeline {
+            inner: Box::new(pipeline),
+        }
+    }
+}
+
+impl<IN, OUT> Linkable for BoxedPipeline<IN, OUT> where OUT: Send + Sync {
+    type OUT = OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        self.inner.stages()
+    }
+}
+
+#[async_trait::async_trait]
+impl<IN, OUT> Pipeline for BoxedPipeline<IN, OUT>
+    where IN: Send + Sync,
+          OUT: Send + Sync {
+    type IN = IN;
+    async fn process(self: &Self, input: IN) -> Result<OUT, Error> {
+        self.inner.process(input).await
+    }
+}
+
+/**
+ * stages from T to T run in the order they were added, which can happen
+ * at runtime, e.g. by plugins. an empty chain gives its input back.
+ * each stage is a BoxedPipeline, so a dynamic call per stage.
+ **/
+pub struct Chain<T> {
+    stages: Vec<BoxedPipeline<T, T>>,
+}
+
+impl<T> Default for Chain<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Chain<T> {
+    pub fn new() -> Self {
+        Chain {
+            stages: Vec::new(),
+        }
+    }
+
+    pub fn stage<P>(mut self: Self, pipeline: P) -> Self where P: Pipeline<IN=T, OUT=T> + Send + Sync + 'static {
+        self.push(pipeline);
+        self
+    }
+
+    pub fn push<P>(self: &mut Self, pipeline: P) where P: Pipeline<IN=T, OUT=T> + Send + Sync + 'static {
+        self.stages.push(BoxedPipeline::new(pipeline));
+    }
+
+    pub fn len(self: &Self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        self.stages.is_empty()
+    }
+}
+
+impl<T> Linkable for Chain<T> where T: Send + Sync {
+    type OUT = T;
+    // every stage added keeps its own indexes, so it is listed as a sub-pipeline
+    fn stages(self: &Self) -> Vec<Stage> {
+        self.stages.iter().map(|stage| Stage::new("stage", None).branch("runs", stage.stages())).collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl<T> Pipeline for Chain<T> where T: Send + Sync {
+    type IN = T;
+    async fn process(self: &Self, input: T) -> Result<T, Error> {
+        let mut value = input;
+        for stage in &self.stages {
+            value = stage.process(value).await?;
+        }
+        Ok(value)
+    }
+}
@@ -0,0 +1,145 @@
+use std::marker::PhantomData;
+
+use crate::pipeline::link::{Error, Linkable, Pipeline, Start};
+use crate::pipeline::stage::{append, Stage};
+
+type Case<T, U> = Box<dyn Pipeline<IN=T, OUT=U> + Send + Sync>;
+
+/**
+ * sends the output to `then` when the predicate holds, to `otherwise`
+ * when it does not. both sub-pipelines start from the output and end
+ * with the same type.
+ **/
+pub struct Branch<P, C, A, B> {
+    pub(crate) prev: P,
+    pub(crate) predicate: C,
+    pub(crate) then: A,
+    pub(crate) otherwise: B,
+}
+
+impl<P, C, A, B> Linkable for Branch<P, C, A, B>
+    where P: Linkable + Send + Sync,
+          C: Fn(&P::OUT) -> bool + Send + Sync,
+          A: Pipeline<IN=P::OUT> + Send + Sync,
+          B: Pipeline<IN=P::OUT, OUT=A::OUT> + Send + Sync {
+    type OUT = A::OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("branch", None)
+            .branch("then", self.then.stages())
+            .branch("otherwise", self.otherwise.stages()))
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, C, A, B> Pipeline for Branch<P, C, A, B>
+    where P: Pipeline + Send + Sync,
+          C: Fn(&P::OUT) -> bool + Send + Sync,
+          A: Pipeline<IN=P::OUT> + Send + Sync,
+          B: Pipeline<IN=P::OUT, OUT=A::OUT> + Send + Sync {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<A::OUT, Error> {
+        let out = self.prev.process(input).await?;
+        if (self.predicate)(&out) {
+            self.then.process(out).await
+        } else {
+            self.otherwise.process(out).await
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<IN, C, A, B> Pipeline for Branch<Start<IN>, C, A, B>
+    where IN: Send + Sync,
+          C: Fn(&IN) -> bool + Send + Sync,
+          A: Pipeline<IN=IN> + Send + Sync,
+          B: Pipeline<IN=IN, OUT=A::OUT> + Send + Sync {
+    type IN = IN;
+    async fn process(self: &Self, input: IN) -> Result<A::OUT, Error> {
+        if (self.predicate)(&input) {
+            self.then.process(input).await
+        } else {
+            self.otherwise.process(input).await
+        }
+    }
+}
+
+/**
+ * sends the output to the sub-pipeline of the first case equal to its key,
+ * e.g. the variant of an enum output. an output matching no case goes to
+ * `otherwise`, or fails when there is none.
+ **/
+pub struct Switch<P: Linkable, KF, K, U> {
+    pub(crate) prev: P,
+    pub(crate) key: KF,
+    pub(crate) cases: Vec<(K, Case<P::OUT, U>)>,
+    pub(crate) otherwise: Option<Case<P::OUT, U>>,
+    pub(crate) _out: PhantomData<fn() -> U>,
+}
+
+impl<P: Linkable, KF, K, U> Switch<P, KF, K, U> {
+    pub fn case<S>(mut self: Self, key: K, pipeline: S) -> Self
+        where S: Pipeline<IN=P::OUT, OUT=U> + Send + Sync + 'static {
+        self.cases.push((key, Box::new(pipeline)));
+        self
+    }
+
+    pub fn otherwise<S>(mut self: Self, pipeline: S) -> Self
+        where S: Pipeline<IN=P::OUT, OUT=U> + Send + Sync + 'static {
+        self.otherwise = Some(Box::new(pipeline));
+        self
+    }
+
+    fn select(self: &Self, out: &P::OUT) -> Result<&Case<P::OUT, U>, Error> where KF: Fn(&P::OUT) -> K, K: PartialEq {
+        let key = (self.key)(out);
+        self.cases.iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, p)| p)
+            .or(self.otherwise.as_ref())
+            .ok_or_else(|| "no case matches the output".into())
+    }
+}
+
+impl<P, KF, K, U> Linkable for Switch<P, KF, K, U>
+    where P: Linkable + Send + Sync,
+          KF: Fn(&P::OUT) -> K + Send + Sync,
+          K: PartialEq + Send + Sync,
+          U: Send + Sync {
+    type OUT = U;
+    fn stages(self: &Self) -> Vec<Stage> {
+        let mut stage = Stage::new("switch", None);
+        for (i, (_, case)) in self.cases.iter().enumerate() {
+            stage = stage.branch(&format!("case {}", i), case.stages());
+        }
+        if let Some(otherwise) = &self.otherwise {
+            stage = stage.branch("otherwise", otherwise.stages());
+        }
+        append(self.prev.stages(), stage)
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, KF, K, U> Pipeline for Switch<P, KF, K, U>
+    where P: Pipeline + Send + Sync,
+          KF: Fn(&P::OUT) -> K + Send + Sync,
+          K: PartialEq + Send + Sync,
+          U: Send + Sync {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<U, Error> {
+        let out = self.prev.process(input).await?;
+        let case = self.select(&out)?;
+        case.process(out).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<IN, KF, K, U> Pipeline for Switch<Start<IN>, KF, K, U>
+    where IN: Send + Sync,
+          KF: Fn(&IN) -> K + Send + Sync,
+          K: PartialEq + Send + Sync,
+          U: Send + Sync {
+    type IN = IN;
+    async fn process(self: &Self, input: IN) -> Result<U, Error> {
+        let case = self.select(&input)?;
+        case.process(input).await
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::pipeline::link::{Error, Linkable, Pipeline};
+use crate::pipeline::stage::{append, Stage};
+
+/**
+ * map holding at most `capacity` entries, each for its own time to live.
+ * a full map evicts the least recently read or written entry.
+ **/
+pub(crate) struct Lru<K, V> {
+    entries: HashMap<K, (V, Instant, u64)>,
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    capacity: usize,
+}
+
+impl<K: Clone + Eq + Hash, V> Lru<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub(crate) fn get(self: &mut Self, key: &K) -> Option<&mut V> {
+        let (_, expires, _) = self.entries.get(key)?;
+        if *expires <= Instant::now() {
+            self.remove(key);
+            return None;
+        }
+        self.tick += 1;
+        let (value, _, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(value)
+    }
+
+    pub(crate) fn insert(self: &mut Self, key: K, value: V, ttl: Duration) {
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, Instant::now() + ttl, self.tick));
+    }
+
+    pub(crate) fn remove(self: &mut Self, key: &K) -> Option<V> {
+        let (value, _, used) = self.entries.remove(key)?;
+        self.order.remove(&used);
+        Some(value)
+    }
+}
+
+type Flights<K> = Mutex<HashMap<K, Arc<tokio::sync::Mutex<()>>>>;
+
+// a run or wait for `key`, leaving the flights when done or cancelled unless others still wait
+struct Flight<'a, K: Eq + Hash> {
+    flights: &'a Flights<K>,
+    key: K,
+    flight: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl<K: Eq + Hash> Drop for Flight<'_, K> {
+    fn drop(&mut self) {
+        if let Ok(mut flights) = self.flights.lock() {
+            // held by the map and by us only
+            let last = flights.get(&self.key).is_some_and(|f| Arc::ptr_eq(f, &self.flight))
+                && Arc::strong_count(&self.flight) <= 2;
+            if last {
+                flights.remove(&self.key);
+            }
+        }
+    }
+}
+
+/**
+ * memoizes the output of the previous stages by the key of their input,
+ * for `ttl` and up to `capacity` keys. concurrent misses on the same key
+ * run the stages once, the others wait and read the cached output.
+ * errors are not cached.
+ **/
+pub struct Cached<P: Linkable, KF, K> {
+    pub(crate) prev: P,
+    pub(crate) key: KF,
+    pub(crate) ttl: Duration,
+    pub(crate) store: Mutex<Lru<K, P::OUT>>,
+    pub(crate) flights: Flights<K>,
+}
+
+impl<P, KF, K> Cached<P, KF, K>
+    where P: Linkable,
+          P::OUT: Clone,
+          K: Clone + Eq + Hash {
+    pub(crate) fn new(prev: P, key: KF, ttl: Duration, capacity: usize) -> Self {
+        Cached {
+            prev,
+            key,
+            ttl,
+            store: Mutex::new(Lru::new(capacity)),
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn hit(self: &Self, key: &K) -> Option<P::OUT> {
+        self.store.lock().unwrap().get(key).map(|out| out.clone())
+    }
+}
+
+impl<P, KF, K> Linkable for Cached<P, KF, K>
+    where P: Linkable + Send + Sync,
+          P::OUT: Clone,
+          KF: Send + Sync,
+          K: Send + Sync {
+    type OUT = P::OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("cached", None))
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, KF, K> Pipeline for Cached<P, KF, K>
+    where P: Pipeline + Send + Sync,
+          P::OUT: Clone,
+          KF: Fn(&P::IN) -> K + Send + Sync,
+          K: Clone + Eq + Hash + Send + Sync {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<P::OUT, Error> {
+        let key = (self.key)(&input);
+        if let Some(out) = self.hit(&key) {
+            return Ok(out);
+        }
+        let flight = Flight {
+            flights: &self.flights,
+            key: key.clone(),
+            flight: self.flights.lock().unwrap().entry(key.clone()).or_default().clone(),
+        };
+        let _running = flight.flight.lock().await;
+        if let Some(out) = self.hit(&key) {
+            return Ok(out);
+        }
+        let res = self.prev.process(input).await;
+        if let Ok(out) = &res {
+            self.store.lock().unwrap().insert(key, out.clone(), self.ttl);
+        }
+        res
+    }
+}
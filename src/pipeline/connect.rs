@@ -1,8 +1,10 @@
-use crate::pipeline::link::{Error, ErrorFuc, Linkable, Pipeline, Start};
+use crate::pipeline::link::{stage_span, Error, ErrorFuc, Linkable, Pipeline, Start};
+use crate::pipeline::stage::{append, Stage, StageError};
 
 pub struct Connect<P, F> {
     pub(crate) prev: P,
     pub(crate) next: F,
+    pub(crate) name: Option<&'static str>,
 }
 
 impl<NXT, F, L> Linkable for Connect<L, F>
@@ -10,6 +12,9 @@ impl<NXT, F, L> Linkable for Connect<L, F>
           NXT: Send + Sync,
           L: Linkable + Send + Sync {
     type OUT = NXT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("then", self.name))
+    }
 }
 
 #[async_trait::async_trait]
@@ -21,6 +26,7 @@ impl<NXT, F, P> Pipeline for Connect<P, F>
     type IN = P::IN;
     async fn process(self: &Self, input: Self::IN) -> Result<NXT, Error> {
         let out = self.prev.process(input).await?;
+        let _span = stage_span(self.name).entered();
         Ok((self.next)(out))
     }
 }
@@ -31,6 +37,9 @@ impl<NXT, F, P> Linkable for Connect<P, ErrorFuc<F>>
           NXT: Send + Sync,
           P: Linkable + Send + Sync {
     type OUT = NXT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("then_result", self.name))
+    }
 }
 
 #[async_trait::async_trait]
@@ -42,7 +51,8 @@ impl<NXT, F, P> Pipeline for Connect<P, ErrorFuc<F>>
     type IN = P::IN;
     async fn process(self: &Self, input: Self::IN) -> Result<NXT, Error> {
         let out = self.prev.process(input).await?;
-        (self.next.f)(out)
+        let _span = stage_span(self.name).entered();
+        (self.next.f)(out).map_err(|e| StageError::wrap(e, self.prev.stages().len(), self.name))
     }
 }
 
@@ -54,7 +64,8 @@ impl<NXT, F, IN> Pipeline for Connect<Start<IN>, ErrorFuc<F>>
 {
     type IN = IN;
     async fn process(self: &Self, input: Self::IN) -> Result<NXT, Error> {
-        (self.next.f)(input)
+        let _span = stage_span(self.name).entered();
+        (self.next.f)(input).map_err(|e| StageError::wrap(e, 0, self.name))
     }
 }
 
@@ -66,6 +77,7 @@ impl<NXT, F, IN> Pipeline for Connect<Start<IN>, F>
 {
     type IN = IN;
     async fn process(self: &Self, input: IN) -> Result<NXT, Error> {
+        let _span = stage_span(self.name).entered();
         Ok((self.next)(input))
     }
 }
\ No newline at end of file
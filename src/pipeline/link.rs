@@ -1,29 +1,71 @@
 use std::future::Future;
 use std::marker::PhantomData;
+use std::time::Duration;
+use tracing::Span;
 use crate::pipeline::async_connect::AsyncConnect;
+use crate::pipeline::boxed::BoxedPipeline;
+use crate::pipeline::branch::{Branch, Switch};
+use crate::pipeline::cache::Cached;
 use crate::pipeline::connect::Connect;
+use crate::pipeline::parallel::{FanOut, Forks, Join, Race, Racers};
+use crate::pipeline::pipe::Pipe;
+use crate::pipeline::recover::{MapErr, OrElse, Recover};
+use crate::pipeline::resilience::{Breaker, CircuitBreaker, Retry, RetryPolicy, Timeout};
+use crate::pipeline::stage::{self, Stage};
 
 pub type Error = Box<dyn std::error::Error>;
 
 pub trait Linkable {
     type OUT: Send + Sync;
+
+    // stages added so far, in order, see Pipeline::describe
+    fn stages(self: &Self) -> Vec<Stage> {
+        Vec::new()
+    }
+
     fn then_async<F, FUT, NXT>(self: Self, f: F) -> AsyncConnect<Self, F>
         where F: Fn(Self::OUT) -> FUT,
-              FUT: Future<Output=NXT> + Send + Sync,
+              FUT: Future<Output=NXT> + Send,
               Self: Sized {
         AsyncConnect {
             prev: self,
             next: f,
+            name: None,
+        }
+    }
+
+    // like `then_async`, the stage runs inside a child span carrying `name`
+    fn then_async_named<F, FUT, NXT>(self: Self, name: &'static str, f: F) -> AsyncConnect<Self, F>
+        where F: Fn(Self::OUT) -> FUT,
+              FUT: Future<Output=NXT> + Send,
+              Self: Sized {
+        AsyncConnect {
+            prev: self,
+            next: f,
+            name: Some(name),
         }
     }
 
     fn then_async_result<F, FUT, NXT>(self: Self, f: F) -> AsyncConnect<Self, ErrorFuc<F>>
         where F: Fn(Self::OUT) -> FUT,
-              FUT: Future<Output=Result<NXT, Error>> + Send + Sync,
+              FUT: Future<Output=Result<NXT, Error>> + Send,
+              Self: Sized {
+        AsyncConnect {
+            prev: self,
+            next: ErrorFuc::new(f),
+            name: None,
+        }
+    }
+
+    // like `then_async_result`, the name also goes into the span and the StageError
+    fn then_async_result_named<F, FUT, NXT>(self: Self, name: &'static str, f: F) -> AsyncConnect<Self, ErrorFuc<F>>
+        where F: Fn(Self::OUT) -> FUT,
+              FUT: Future<Output=Result<NXT, Error>> + Send,
               Self: Sized {
         AsyncConnect {
             prev: self,
             next: ErrorFuc::new(f),
+            name: Some(name),
         }
     }
 
@@ -33,6 +75,18 @@ pub trait Linkable {
         Connect {
             prev: self,
             next: f,
+            name: None,
+        }
+    }
+
+    // like `then`, the stage runs inside a child span carrying `name`
+    fn then_named<F, NXT>(self: Self, name: &'static str, f: F) -> Connect<Self, F>
+        where F: Fn(Self::OUT) -> NXT,
+              Self: Sized {
+        Connect {
+            prev: self,
+            next: f,
+            name: Some(name),
         }
     }
 
@@ -42,6 +96,155 @@ pub trait Linkable {
         Connect {
             prev: self,
             next: ErrorFuc::new(f),
+            name: None,
+        }
+    }
+
+    // like `then_result`, the name also goes into the span and the StageError
+    fn then_result_named<F, NXT>(self: Self, name: &'static str, f: F) -> Connect<Self, ErrorFuc<F>>
+        where F: Fn(Self::OUT) -> Result<NXT, Error>,
+              Self: Sized {
+        Connect {
+            prev: self,
+            next: ErrorFuc::new(f),
+            name: Some(name),
+        }
+    }
+
+    fn branch<C, A, B>(self: Self, predicate: C, then: A, otherwise: B) -> Branch<Self, C, A, B>
+        where C: Fn(&Self::OUT) -> bool,
+              A: Pipeline<IN=Self::OUT>,
+              B: Pipeline<IN=Self::OUT, OUT=A::OUT>,
+              Self: Sized {
+        Branch {
+            prev: self,
+            predicate,
+            then,
+            otherwise,
+        }
+    }
+
+    // add the cases with `Switch::case` and `Switch::otherwise`
+    fn switch<KF, K, U>(self: Self, key: KF) -> Switch<Self, KF, K, U>
+        where KF: Fn(&Self::OUT) -> K,
+              K: PartialEq,
+              Self: Sized {
+        Switch {
+            prev: self,
+            key,
+            cases: Vec::new(),
+            otherwise: None,
+            _out: PhantomData,
+        }
+    }
+
+    // e.g. `.join((users, orders))` gives `(User, Vec<Order>)`, the first error stops the others
+    fn join<T>(self: Self, forks: T) -> Join<Self, T>
+        where T: Forks<Self::OUT>,
+              Self: Sized {
+        Join {
+            prev: self,
+            forks,
+        }
+    }
+
+    fn race<T>(self: Self, racers: T) -> Race<Self, T>
+        where T: Racers<Self::OUT>,
+              Self: Sized {
+        Race {
+            prev: self,
+            racers,
+        }
+    }
+
+    fn fan_out<S>(self: Self, pipeline: S, limit: usize) -> FanOut<Self, S>
+        where S: Pipeline,
+              Self::OUT: IntoIterator<Item=S::IN>,
+              Self: Sized {
+        FanOut {
+            prev: self,
+            pipeline,
+            limit,
+        }
+    }
+
+    /**
+     * continue with the stages of `fragment`, a pipeline built on its own
+     * from `begin::<Self::OUT>()`, so it can be reused in several chains.
+     **/
+    fn pipe<F>(self: Self, fragment: F) -> Pipe<Self, F>
+        where F: Pipeline<IN=Self::OUT>,
+              Self: Sized {
+        Pipe {
+            prev: self,
+            fragment,
+        }
+    }
+
+    // erase the type of the stages so far, see BoxedPipeline
+    fn boxed(self: Self) -> BoxedPipeline<<Self as Pipeline>::IN, Self::OUT>
+        where Self: Pipeline + Sized + Send + Sync + 'static {
+        BoxedPipeline::new(self)
+    }
+
+    // memoize the output of the stages so far by `key` of their input
+    fn cached<KF, K>(self: Self, key: KF, ttl: Duration, capacity: usize) -> Cached<Self, KF, K>
+        where Self: Pipeline + Sized,
+              Self::OUT: Clone,
+              KF: Fn(&<Self as Pipeline>::IN) -> K,
+              K: Clone + Eq + std::hash::Hash {
+        Cached::new(self, key, ttl, capacity)
+    }
+
+    // run the stages so far again while they fail, `Self::IN` must be Clone
+    fn retry(self: Self, policy: RetryPolicy) -> Retry<Self>
+        where Self: Pipeline + Sized {
+        Retry {
+            prev: self,
+            policy,
+        }
+    }
+
+    fn timeout(self: Self, duration: Duration) -> Timeout<Self>
+        where Self: Pipeline + Sized {
+        Timeout {
+            prev: self,
+            duration,
+        }
+    }
+
+    fn circuit_breaker(self: Self, breaker: CircuitBreaker) -> Breaker<Self>
+        where Self: Pipeline + Sized {
+        Breaker {
+            prev: self,
+            breaker,
+        }
+    }
+
+    fn map_err<F>(self: Self, f: F) -> MapErr<Self, F>
+        where F: Fn(Error) -> Error,
+              Self: Sized {
+        MapErr {
+            prev: self,
+            f,
+        }
+    }
+
+    fn recover<F>(self: Self, f: F) -> Recover<Self, F>
+        where F: Fn(Error) -> Self::OUT,
+              Self: Sized {
+        Recover {
+            prev: self,
+            f,
+        }
+    }
+
+    fn or_else<F>(self: Self, f: F) -> OrElse<Self, F>
+        where F: Fn(Error) -> Result<Self::OUT, Error>,
+              Self: Sized {
+        OrElse {
+            prev: self,
+            f,
         }
     }
 }
@@ -51,12 +254,22 @@ pub trait Pipeline: Linkable {
     type IN: Send + Sync;
     // todo return Result
     async fn process(self: &Self, input: Self::IN) -> Result<Self::OUT, Error>;
+
+    // one line per stage with its index, sub-pipelines indented under them
+    fn describe(self: &Self) -> String {
+        stage::describe(&self.stages())
+    }
+
+    // the stage graph in Graphviz DOT, e.g. for `dot -Tsvg`
+    fn dot(self: &Self) -> String {
+        stage::dot(&self.stages())
+    }
 }
 
 pub fn begin<T>() -> Start<T> {
-    return Start {
+    Start {
         _p: Default::default()
-    };
+    }
 }
 
 
@@ -68,6 +281,14 @@ impl<IN: Send + Sync> Linkable for Start<IN> {
     type OUT = IN;
 }
 
+// Child span for a named stage, disabled when the stage has no name.
+pub(crate) fn stage_span(name: Option<&'static str>) -> Span {
+    match name {
+        Some(name) => tracing::info_span!("stage", name = name),
+        None => Span::none(),
+    }
+}
+
 pub struct ErrorFuc<F> {
     pub f: F,
 }
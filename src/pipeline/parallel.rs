@@ -0,0 +1,212 @@
+use futures_util::future::{select_ok, try_join, try_join3, try_join4};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+
+use crate::pipeline::link::{Error, Linkable, Pipeline, Start};
+use crate::pipeline::stage::{append, Stage};
+
+/**
+ * tuple of pipelines fed with clones of the same input, see Linkable::join.
+ * implemented for tuples of 2 to 4 pipelines.
+ **/
+#[async_trait::async_trait]
+pub trait Forks<IN>: Send + Sync {
+    type OUT: Send + Sync;
+    async fn fork(self: &Self, input: IN) -> Result<Self::OUT, Error>;
+    fn stages(self: &Self) -> Vec<Vec<Stage>>;
+}
+
+/**
+ * tuple of pipelines with the same output racing on clones of the same
+ * input, see Linkable::race. implemented for tuples of 2 to 4 pipelines.
+ **/
+#[async_trait::async_trait]
+pub trait Racers<IN>: Send + Sync {
+    type OUT: Send + Sync;
+    async fn race(self: &Self, input: IN) -> Result<Self::OUT, Error>;
+    fn stages(self: &Self) -> Vec<Vec<Stage>>;
+}
+
+macro_rules! tuple_impls {
+    ($join:ident, $first:ident $a:ident, $($name:ident $p:ident),+) => {
+        #[async_trait::async_trait]
+        impl<IN, $first, $($name),+> Forks<IN> for ($first, $($name),+)
+            where IN: Clone + Send + Sync + 'static,
+                  $first: Pipeline<IN=IN> + Send + Sync,
+                  $($name: Pipeline<IN=IN> + Send + Sync),+ {
+            type OUT = ($first::OUT, $($name::OUT),+);
+            async fn fork(self: &Self, input: IN) -> Result<Self::OUT, Error> {
+                let ($a, $($p),+) = self;
+                $join($a.process(input.clone()), $($p.process(input.clone())),+).await
+            }
+            fn stages(self: &Self) -> Vec<Vec<Stage>> {
+                let ($a, $($p),+) = self;
+                vec![$a.stages(), $($p.stages()),+]
+            }
+        }
+
+        #[async_trait::async_trait]
+        impl<IN, $first, $($name),+> Racers<IN> for ($first, $($name),+)
+            where IN: Clone + Send + Sync + 'static,
+                  $first: Pipeline<IN=IN> + Send + Sync,
+                  $($name: Pipeline<IN=IN, OUT=$first::OUT> + Send + Sync),+ {
+            type OUT = $first::OUT;
+            async fn race(self: &Self, input: IN) -> Result<Self::OUT, Error> {
+                let ($a, $($p),+) = self;
+                // the error of the last one failing when they all fail
+                select_ok(vec![$a.process(input.clone()), $($p.process(input.clone())),+]).await
+                    .map(|(out, _)| out)
+            }
+            fn stages(self: &Self) -> Vec<Vec<Stage>> {
+                let ($a, $($p),+) = self;
+                vec![$a.stages(), $($p.stages()),+]
+            }
+        }
+    };
+}
+
+tuple_impls!(try_join, A a, B b);
+tuple_impls!(try_join3, A a, B b, C c);
+tuple_impls!(try_join4, A a, B b, C c, D d);
+
+// Runs the forks concurrently and gives the tuple of their outputs.
+pub struct Join<P, T> {
+    pub(crate) prev: P,
+    pub(crate) forks: T,
+}
+
+impl<P, T> Linkable for Join<P, T>
+    where P: Linkable + Send + Sync,
+          T: Forks<P::OUT> {
+    type OUT = T::OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        let stage = self.forks.stages().into_iter().enumerate()
+            .fold(Stage::new("join", None), |stage, (i, fork)| stage.branch(&format!("fork {}", i), fork));
+        append(self.prev.stages(), stage)
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, T> Pipeline for Join<P, T>
+    where P: Pipeline + Send + Sync,
+          T: Forks<P::OUT> {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<T::OUT, Error> {
+        let out = self.prev.process(input).await?;
+        self.forks.fork(out).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<IN, T> Pipeline for Join<Start<IN>, T>
+    where IN: Send + Sync,
+          T: Forks<IN> {
+    type IN = IN;
+    async fn process(self: &Self, input: IN) -> Result<T::OUT, Error> {
+        self.forks.fork(input).await
+    }
+}
+
+// Gives the output of the first racer succeeding, the others are dropped.
+pub struct Race<P, T> {
+    pub(crate) prev: P,
+    pub(crate) racers: T,
+}
+
+impl<P, T> Linkable for Race<P, T>
+    where P: Linkable + Send + Sync,
+          T: Racers<P::OUT> {
+    type OUT = T::OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        let stage = self.racers.stages().into_iter().enumerate()
+            .fold(Stage::new("race", None), |stage, (i, racer)| stage.branch(&format!("racer {}", i), racer));
+        append(self.prev.stages(), stage)
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, T> Pipeline for Race<P, T>
+    where P: Pipeline + Send + Sync,
+          T: Racers<P::OUT> {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<T::OUT, Error> {
+        let out = self.prev.process(input).await?;
+        self.racers.race(out).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<IN, T> Pipeline for Race<Start<IN>, T>
+    where IN: Send + Sync,
+          T: Racers<IN> {
+    type IN = IN;
+    async fn process(self: &Self, input: IN) -> Result<T::OUT, Error> {
+        self.racers.race(input).await
+    }
+}
+
+/**
+ * runs `pipeline` on every item of the output, at most `limit` at a time,
+ * and gives the outputs in the order of the items. the first error stops
+ * the items still running.
+ **/
+pub struct FanOut<P, S> {
+    pub(crate) prev: P,
+    pub(crate) pipeline: S,
+    pub(crate) limit: usize,
+}
+
+impl<P, S> FanOut<P, S> where S: Pipeline + Send + Sync {
+    async fn fan_out<I>(self: &Self, items: I) -> Result<Vec<S::OUT>, Error>
+        where I: IntoIterator<Item=S::IN> {
+        let mut pending = items.into_iter().collect::<Vec<_>>().into_iter().enumerate();
+        let mut outs: Vec<Option<S::OUT>> = (0..pending.len()).map(|_| None).collect();
+        let mut running = FuturesUnordered::new();
+        let run = |(i, item)| {
+            let fut = self.pipeline.process(item);
+            async move { fut.await.map(|out| (i, out)) }
+        };
+        for item in pending.by_ref().take(self.limit.max(1)) {
+            running.push(run(item));
+        }
+        while let Some(done) = running.next().await {
+            let (i, out) = done?;
+            outs[i] = Some(out);
+            if let Some(item) = pending.next() {
+                running.push(run(item));
+            }
+        }
+        Ok(outs.into_iter().flatten().collect())
+    }
+}
+
+impl<P, S> Linkable for FanOut<P, S>
+    where P: Linkable + Send + Sync,
+          P::OUT: IntoIterator<Item=S::IN>,
+          S: Pipeline + Send + Sync {
+    type OUT = Vec<S::OUT>;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("fan_out", None).branch("item", self.pipeline.stages()))
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, S> Pipeline for FanOut<P, S>
+    where P: Pipeline + Send + Sync,
+          P::OUT: IntoIterator<Item=S::IN>,
+          S: Pipeline + Send + Sync {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<Vec<S::OUT>, Error> {
+        let out = self.prev.process(input).await?;
+        self.fan_out(out).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<IN, S> Pipeline for FanOut<Start<IN>, S>
+    where IN: IntoIterator<Item=S::IN> + Send + Sync,
+          S: Pipeline + Send + Sync {
+    type IN = IN;
+    async fn process(self: &Self, input: IN) -> Result<Vec<S::OUT>, Error> {
+        self.fan_out(input).await
+    }
+}
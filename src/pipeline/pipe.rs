@@ -0,0 +1,39 @@
+use crate::pipeline::link::{Error, Linkable, Pipeline, Start};
+use crate::pipeline::stage::{append, Stage};
+
+// Feeds the output of the previous stages to a pipeline written on its own.
+pub struct Pipe<P, F> {
+    pub(crate) prev: P,
+    pub(crate) fragment: F,
+}
+
+impl<P, F> Linkable for Pipe<P, F>
+    where P: Linkable + Send + Sync,
+          F: Pipeline<IN=P::OUT> + Send + Sync {
+    type OUT = F::OUT;
+    // the fragment keeps its own indexes, so it is listed as a sub-pipeline
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("pipe", None).branch("fragment", self.fragment.stages()))
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, F> Pipeline for Pipe<P, F>
+    where P: Pipeline + Send + Sync,
+          F: Pipeline<IN=P::OUT> + Send + Sync {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<F::OUT, Error> {
+        let out = self.prev.process(input).await?;
+        self.fragment.process(out).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<IN, F> Pipeline for Pipe<Start<IN>, F>
+    where IN: Send + Sync,
+          F: Pipeline<IN=IN> + Send + Sync {
+    type IN = IN;
+    async fn process(self: &Self, input: IN) -> Result<F::OUT, Error> {
+        self.fragment.process(input).await
+    }
+}
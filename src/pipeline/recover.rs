@@ -0,0 +1,77 @@
+use crate::pipeline::link::{Error, Linkable, Pipeline};
+use crate::pipeline::stage::{append, Stage};
+
+// Replaces the error of the previous stages.
+pub struct MapErr<P, F> {
+    pub(crate) prev: P,
+    pub(crate) f: F,
+}
+
+impl<P, F> Linkable for MapErr<P, F>
+    where P: Linkable + Send + Sync,
+          F: Fn(Error) -> Error + Send + Sync {
+    type OUT = P::OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("map_err", None))
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, F> Pipeline for MapErr<P, F>
+    where P: Pipeline + Send + Sync,
+          F: Fn(Error) -> Error + Send + Sync {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<P::OUT, Error> {
+        self.prev.process(input).await.map_err(&self.f)
+    }
+}
+
+// Turns the error of the previous stages into an output.
+pub struct Recover<P, F> {
+    pub(crate) prev: P,
+    pub(crate) f: F,
+}
+
+impl<P, F> Linkable for Recover<P, F>
+    where P: Linkable + Send + Sync,
+          F: Fn(Error) -> P::OUT + Send + Sync {
+    type OUT = P::OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("recover", None))
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, F> Pipeline for Recover<P, F>
+    where P: Pipeline + Send + Sync,
+          F: Fn(Error) -> P::OUT + Send + Sync {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<P::OUT, Error> {
+        Ok(self.prev.process(input).await.unwrap_or_else(&self.f))
+    }
+}
+
+// Gives the error of the previous stages a second chance, which may fail too.
+pub struct OrElse<P, F> {
+    pub(crate) prev: P,
+    pub(crate) f: F,
+}
+
+impl<P, F> Linkable for OrElse<P, F>
+    where P: Linkable + Send + Sync,
+          F: Fn(Error) -> Result<P::OUT, Error> + Send + Sync {
+    type OUT = P::OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("or_else", None))
+    }
+}
+
+#[async_trait::async_trait]
+impl<P, F> Pipeline for OrElse<P, F>
+    where P: Pipeline + Send + Sync,
+          F: Fn(Error) -> Result<P::OUT, Error> + Send + Sync {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<P::OUT, Error> {
+        self.prev.process(input).await.or_else(&self.f)
+    }
+}
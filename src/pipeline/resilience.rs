@@ -0,0 +1,279 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::{sleep, Instant};
+
+use crate::pipeline::link::{Error, Linkable, Pipeline};
+use crate::pipeline::stage::{append, Stage};
+
+type RetryOn = Arc<dyn Fn(&Error) -> bool + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backoff {
+    Fixed(Duration),
+    // `initial` doubled after every attempt, up to `max`
+    Exponential { initial: Duration, max: Duration },
+}
+
+/**
+ * how a failing pipeline is run again: at most `max_attempts` runs in all,
+ * waiting for the backoff between them, only for the errors `retry_on`
+ * accepts (every error by default).
+ **/
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Backoff,
+    jitter: bool,
+    retry_on: Option<RetryOn>,
+}
+
+impl RetryPolicy {
+    pub fn fixed(delay: Duration, max_attempts: u32) -> Self {
+        Self::new(Backoff::Fixed(delay), max_attempts)
+    }
+
+    pub fn exponential(initial: Duration, max: Duration, max_attempts: u32) -> Self {
+        Self::new(Backoff::Exponential { initial, max }, max_attempts)
+    }
+
+    fn new(backoff: Backoff, max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts,
+            backoff,
+            jitter: false,
+            retry_on: None,
+        }
+    }
+
+    // Wait a random time between half and all of the backoff.
+    pub fn jitter(mut self: Self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    // `f` gets the StageError of the failing stage, see stage::cause to downcast it.
+    pub fn retry_on<F>(mut self: Self, f: F) -> Self where F: Fn(&Error) -> bool + Send + Sync + 'static {
+        self.retry_on = Some(Arc::new(f));
+        self
+    }
+
+    // Wait before the run following the `attempt`th one, counted from 1.
+    pub fn delay(self: &Self, attempt: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => initial
+                .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+                .map_or(max, |d| d.min(max)),
+        };
+        if self.jitter {
+            delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+        } else {
+            delay
+        }
+    }
+
+    fn retries(self: &Self, attempt: u32, err: &Error) -> bool {
+        attempt < self.max_attempts && self.retry_on.as_ref().is_none_or(|f| f(err))
+    }
+}
+
+// Runs the previous stages again while they fail, the input must be Clone.
+pub struct Retry<P> {
+    pub(crate) prev: P,
+    pub(crate) policy: RetryPolicy,
+}
+
+impl<P> Linkable for Retry<P> where P: Linkable + Send + Sync {
+    type OUT = P::OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("retry", None))
+    }
+}
+
+#[async_trait::async_trait]
+impl<P> Pipeline for Retry<P>
+    where P: Pipeline + Send + Sync,
+          P::IN: Clone {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<P::OUT, Error> {
+        let mut attempt = 1;
+        loop {
+            let delay = match self.prev.process(input.clone()).await {
+                Ok(out) => return Ok(out),
+                Err(e) if !self.policy.retries(attempt, &e) => return Err(e),
+                Err(_) => self.policy.delay(attempt),
+            };
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+// Fails the previous stages with tokio's Elapsed error when they take longer than `duration`.
+pub struct Timeout<P> {
+    pub(crate) prev: P,
+    pub(crate) duration: Duration,
+}
+
+impl<P> Linkable for Timeout<P> where P: Linkable + Send + Sync {
+    type OUT = P::OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("timeout", None))
+    }
+}
+
+#[async_trait::async_trait]
+impl<P> Pipeline for Timeout<P> where P: Pipeline + Send + Sync {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<P::OUT, Error> {
+        tokio::time::timeout(self.duration, self.prev.process(input)).await?
+    }
+}
+
+// Error of a stage refused by an open circuit breaker.
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit breaker is open")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    opened: Instant,
+    trials: u32,
+}
+
+/**
+ * opens after `failure_threshold` consecutive failures and refuses every
+ * run with CircuitOpen for `open_for`. it then lets `half_open_trials`
+ * runs through: a success closes it, a failure opens it again.
+ * clones share the state, so use one per downstream across requests.
+ **/
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    half_open_trials: u32,
+    circuit: Arc<Mutex<Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            open_for,
+            half_open_trials: 1,
+            circuit: Arc::new(Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                failures: 0,
+                opened: Instant::now(),
+                trials: 0,
+            })),
+        }
+    }
+
+    pub fn half_open_trials(mut self: Self, trials: u32) -> Self {
+        self.half_open_trials = trials;
+        self
+    }
+
+    pub fn state(self: &Self) -> CircuitState {
+        let mut c = self.circuit.lock().unwrap();
+        if c.state == CircuitState::Open && c.opened.elapsed() >= self.open_for {
+            c.state = CircuitState::HalfOpen;
+            c.trials = 0;
+        }
+        c.state
+    }
+
+    fn acquire(self: &Self) -> Option<Permit<'_>> {
+        let state = self.state();
+        let mut c = self.circuit.lock().unwrap();
+        match state {
+            CircuitState::Closed => Some(Permit { breaker: self, trial: false }),
+            CircuitState::Open => None,
+            CircuitState::HalfOpen if c.trials < self.half_open_trials => {
+                c.trials += 1;
+                Some(Permit { breaker: self, trial: true })
+            }
+            CircuitState::HalfOpen => None,
+        }
+    }
+
+    fn record(self: &Self, success: bool) {
+        let mut c = self.circuit.lock().unwrap();
+        if success {
+            c.state = CircuitState::Closed;
+            c.failures = 0;
+            return;
+        }
+        c.failures += 1;
+        if c.state == CircuitState::HalfOpen || c.failures >= self.failure_threshold {
+            c.state = CircuitState::Open;
+            c.opened = Instant::now();
+        }
+    }
+}
+
+/**
+ * run let through by the breaker. a half-open trial dropped before its
+ * outcome is known, e.g. cancelled by a timeout or a client disconnect,
+ * counts as a failure so the trial is not held forever.
+ **/
+struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    trial: bool,
+}
+
+impl Permit<'_> {
+    fn record(self: Self, success: bool) {
+        self.breaker.record(success);
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.trial {
+            self.breaker.record(false);
+        }
+    }
+}
+
+pub struct Breaker<P> {
+    pub(crate) prev: P,
+    pub(crate) breaker: CircuitBreaker,
+}
+
+impl<P> Linkable for Breaker<P> where P: Linkable + Send + Sync {
+    type OUT = P::OUT;
+    fn stages(self: &Self) -> Vec<Stage> {
+        append(self.prev.stages(), Stage::new("circuit_breaker", None))
+    }
+}
+
+#[async_trait::async_trait]
+impl<P> Pipeline for Breaker<P> where P: Pipeline + Send + Sync {
+    type IN = P::IN;
+    async fn process(self: &Self, input: Self::IN) -> Result<P::OUT, Error> {
+        let permit = self.breaker.acquire().ok_or(CircuitOpen)?;
+        let res = self.prev.process(input).await;
+        permit.record(res.is_ok());
+        res
+    }
+}
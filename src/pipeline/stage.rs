@@ -0,0 +1,137 @@
+use std::fmt;
+use std::fmt::Write;
+
+use crate::pipeline::link::Error;
+
+/**
+ * one stage of a pipeline as listed by Linkable::stages: the combinator
+ * that added it, the name given with the `_named` variants, and the
+ * sub-pipelines it runs, each with a label like `then` or `fork 1`.
+ **/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stage {
+    pub kind: &'static str,
+    pub name: Option<&'static str>,
+    pub branches: Vec<(String, Vec<Stage>)>,
+}
+
+impl Stage {
+    pub fn new(kind: &'static str, name: Option<&'static str>) -> Self {
+        Stage {
+            kind,
+            name,
+            branches: Vec::new(),
+        }
+    }
+
+    pub fn branch(mut self: Self, label: &str, stages: Vec<Stage>) -> Self {
+        self.branches.push((label.to_string(), stages));
+        self
+    }
+
+    fn label(self: &Self) -> String {
+        match self.name {
+            Some(name) => format!("{} ({})", name, self.kind),
+            None => self.kind.to_string(),
+        }
+    }
+}
+
+// Previous stages followed by this one.
+pub(crate) fn append(mut stages: Vec<Stage>, stage: Stage) -> Vec<Stage> {
+    stages.push(stage);
+    stages
+}
+
+/**
+ * error of a failing stage, with its index in the pipeline it was added to,
+ * the one `describe` lists it in, and its name. see `cause` to reach the
+ * error the stage returned.
+ **/
+#[derive(Debug)]
+pub struct StageError {
+    pub index: usize,
+    pub name: Option<&'static str>,
+    pub source: Error,
+}
+
+impl StageError {
+    pub(crate) fn wrap(source: Error, index: usize, name: Option<&'static str>) -> Error {
+        Box::new(StageError { index, name, source })
+    }
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(name) => write!(f, "stage {} ({}): {}", self.index, name, self.source),
+            None => write!(f, "stage {}: {}", self.index, self.source),
+        }
+    }
+}
+
+impl std::error::Error for StageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+// The error returned by the stage, looking through the StageError wrapping it, if it is an `E`.
+pub fn cause<E: std::error::Error + 'static>(err: &Error) -> Option<&E> {
+    let mut err: &(dyn std::error::Error + 'static) = err.as_ref();
+    loop {
+        if let Some(e) = err.downcast_ref::<E>() {
+            return Some(e);
+        }
+        err = err.downcast_ref::<StageError>()?.source.as_ref();
+    }
+}
+
+pub(crate) fn describe(stages: &[Stage]) -> String {
+    fn list(stages: &[Stage], indent: usize, out: &mut String) {
+        for (i, stage) in stages.iter().enumerate() {
+            let _ = writeln!(out, "{:indent$}{} {}", "", i, stage.label(), indent = indent);
+            for (label, sub) in &stage.branches {
+                let _ = writeln!(out, "{:indent$}{}:", "", label, indent = indent + 2);
+                list(sub, indent + 4, out);
+            }
+        }
+    }
+    let mut out = String::new();
+    list(stages, 0, &mut out);
+    out
+}
+
+pub(crate) fn dot(stages: &[Stage]) -> String {
+    // edges into the next node: from which node, with which label
+    type Exits = Vec<(String, Option<String>)>;
+    fn edge(out: &mut String, from: &str, to: &str, label: Option<String>) {
+        let _ = match label {
+            Some(label) => writeln!(out, "    {} -> {} [label=\"{}\"];", from, to, label),
+            None => writeln!(out, "    {} -> {};", from, to),
+        };
+    }
+    fn chain(stages: &[Stage], path: &str, mut exits: Exits, out: &mut String) -> Exits {
+        for (i, stage) in stages.iter().enumerate() {
+            let id = format!("{}s{}", path, i);
+            let _ = writeln!(out, "    {} [label=\"{} {}\"];", id, i, stage.label().replace('"', "\\\""));
+            for (from, label) in exits.drain(..) {
+                edge(out, &from, &id, label);
+            }
+            for (b, (label, sub)) in stage.branches.iter().enumerate() {
+                exits.extend(chain(sub, &format!("{}_{}_", id, b), vec![(id.clone(), Some(label.clone()))], out));
+            }
+            if stage.branches.is_empty() {
+                exits.push((id, None));
+            }
+        }
+        exits
+    }
+    let mut out = String::from("digraph pipeline {\n    rankdir=LR;\n    node [shape=box];\n");
+    out.push_str("    input [shape=point];\n    output [shape=point];\n");
+    for (from, label) in chain(stages, "", vec![("input".to_string(), None)], &mut out) {
+        edge(&mut out, &from, "output", label);
+    }
+    out.push_str("}\n");
+    out
+}
@@ -68,3 +68,362 @@ async fn handle_differen_err() {
 }
 
 
+#[tokio::test]
+async fn test_branch() {
+    use crate::pipeline::link::{begin, Linkable, Pipeline};
+
+    let p = begin::<i32>()
+        .then(|x| x * 2)
+        .branch(|x| *x > 10,
+                begin::<i32>().then(|x| format!("big {}", x)),
+                begin::<i32>().then_result(|x| match x {
+                    0 => Err("zero".into()),
+                    x => Ok(format!("small {}", x)),
+                }))
+        .then(|s| s.to_uppercase());
+    assert_eq!(p.process(6).await.unwrap(), "BIG 12");
+    assert_eq!(p.process(2).await.unwrap(), "SMALL 4");
+    assert_eq!(p.process(0).await.err().unwrap().to_string(), "stage 0: zero");
+
+    #[derive(PartialEq)]
+    enum Kind {
+        Text,
+        Number,
+    }
+    enum Event {
+        Text(String),
+        Number(i64),
+        Other,
+    }
+    let kind = |e: &Event| match e {
+        Event::Text(_) => Some(Kind::Text),
+        Event::Number(_) => Some(Kind::Number),
+        Event::Other => None,
+    };
+    let p = begin::<Event>()
+        .switch(kind)
+        .case(Some(Kind::Text), begin::<Event>().then(|e| match e {
+            Event::Text(s) => s,
+            _ => unreachable!(),
+        }))
+        .case(Some(Kind::Number), begin::<Event>().then_async(|e| async move {
+            match e {
+                Event::Number(n) => (n + 1).to_string(),
+                _ => unreachable!(),
+            }
+        }));
+    assert_eq!(p.process(Event::Text("a".to_string())).await.unwrap(), "a");
+    assert_eq!(p.process(Event::Number(1)).await.unwrap(), "2");
+    assert!(p.process(Event::Other).await.is_err());
+    let p = p.otherwise(begin::<Event>().then(|_| "other".to_string()));
+    assert_eq!(p.process(Event::Other).await.unwrap(), "other");
+}
+
+
+#[tokio::test]
+async fn test_recover() {
+    use crate::pipeline::link::{begin, Linkable, Pipeline};
+
+    let parse = || begin::<&'static str>().then_result(|s| Ok(s.parse::<i32>()?));
+    let p = parse().map_err(|e| format!("bad number: {}", e).into());
+    assert_eq!(p.process("x").await.err().unwrap().to_string(), "bad number: stage 0: invalid digit found in string");
+    let p = parse().recover(|_| -1).then(|x| x * 10);
+    assert_eq!(p.process("x").await.unwrap(), -10);
+    assert_eq!(p.process("3").await.unwrap(), 30);
+    let p = parse().or_else(|e| if e.to_string().contains("empty") { Ok(0) } else { Err(e) });
+    assert_eq!(p.process("").await.unwrap(), 0);
+    assert!(p.process("x").await.is_err());
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn test_parallel() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::time::{sleep, Instant};
+    use crate::pipeline::link::{begin, Linkable, Pipeline};
+
+    let slow = |ms: u64, tag: &'static str| begin::<u64>().then_async(move |x| async move {
+        sleep(Duration::from_millis(ms)).await;
+        format!("{}{}", tag, x)
+    });
+    let failing = |ms: u64| begin::<u64>().then_async_result(move |_| async move {
+        sleep(Duration::from_millis(ms)).await;
+        Err::<String, _>("down".into())
+    });
+
+    let p = begin::<u64>().then(|x| x + 1).join((slow(100, "a"), slow(100, "b"), begin::<u64>().then(|x| x * 2)));
+    let begin_at = Instant::now();
+    assert_eq!(p.process(1).await.unwrap(), ("a2".to_string(), "b2".to_string(), 4));
+    assert_eq!(begin_at.elapsed(), Duration::from_millis(100));
+    let p = begin::<u64>().join((slow(100, "a"), failing(10)));
+    assert_eq!(p.process(1).await.err().unwrap().to_string(), "stage 0: down");
+
+    let p = begin::<u64>().race((slow(100, "slow"), failing(1), slow(20, "fast")));
+    assert_eq!(p.process(1).await.unwrap(), "fast1");
+    let p = begin::<u64>().race((failing(10), failing(20)));
+    assert!(p.process(1).await.is_err());
+
+    let running = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let (r, m) = (running.clone(), peak.clone());
+    let item = begin::<u64>().then_async(move |x| {
+        let (r, m) = (r.clone(), m.clone());
+        async move {
+            m.fetch_max(r.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            sleep(Duration::from_millis(10 * (5 - x))).await;
+            r.fetch_sub(1, Ordering::SeqCst);
+            x * 10
+        }
+    });
+    let p = begin::<u64>().then(|n| 0..n).fan_out(item, 2);
+    assert_eq!(p.process(5).await.unwrap(), vec![0, 10, 20, 30, 40]);
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+    let p = begin::<Vec<u64>>().fan_out(begin::<u64>().then_result(|x| if x == 9 { Err("nine".into()) } else { Ok(x) }), 4);
+    assert_eq!(p.process(vec![1, 9, 3]).await.err().unwrap().to_string(), "stage 0: nine");
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn test_resilience() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+    use tokio::time::{sleep, Instant};
+    use crate::pipeline::link::{begin, Linkable, Pipeline};
+    use crate::pipeline::resilience::{CircuitBreaker, CircuitOpen, CircuitState, RetryPolicy};
+
+    // fails the first `n` calls
+    let flaky = |n: u32, calls: Arc<AtomicU32>| begin::<u32>().then_async_result(move |x| {
+        let calls = calls.clone();
+        async move {
+            match calls.fetch_add(1, Ordering::SeqCst) < n {
+                true => Err("flaky".into()),
+                false => Ok(x),
+            }
+        }
+    });
+
+    let calls = Arc::new(AtomicU32::new(0));
+    let p = flaky(2, calls.clone()).retry(RetryPolicy::exponential(Duration::from_millis(100), Duration::from_secs(1), 3));
+    let begin_at = Instant::now();
+    assert_eq!(p.process(7).await.unwrap(), 7);
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    assert_eq!(begin_at.elapsed(), Duration::from_millis(300));
+
+    let calls = Arc::new(AtomicU32::new(0));
+    let p = flaky(5, calls.clone()).retry(RetryPolicy::fixed(Duration::from_millis(10), 3));
+    assert_eq!(p.process(7).await.err().unwrap().to_string(), "stage 0: flaky");
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+    let calls = Arc::new(AtomicU32::new(0));
+    let p = flaky(5, calls.clone())
+        .retry(RetryPolicy::fixed(Duration::from_millis(10), 3).retry_on(|e| !e.to_string().ends_with("flaky")));
+    assert!(p.process(7).await.is_err());
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    let jittered = RetryPolicy::exponential(Duration::from_millis(100), Duration::from_millis(250), 5).jitter(true);
+    assert!((125..=250).contains(&(jittered.delay(3).as_millis())));
+
+    let p = begin::<u64>().then_async(|ms| async move {
+        sleep(Duration::from_millis(ms)).await;
+        ms
+    }).timeout(Duration::from_millis(50));
+    assert_eq!(p.process(10).await.unwrap(), 10);
+    assert!(p.process(100).await.err().unwrap().is::<tokio::time::error::Elapsed>());
+
+    let breaker = CircuitBreaker::new(2, Duration::from_secs(5));
+    let calls = Arc::new(AtomicU32::new(0));
+    let p = flaky(3, calls.clone()).circuit_breaker(breaker.clone());
+    assert!(p.process(1).await.is_err());
+    assert!(p.process(1).await.is_err());
+    assert_eq!(breaker.state(), CircuitState::Open);
+    assert!(p.process(1).await.err().unwrap().is::<CircuitOpen>());
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    sleep(Duration::from_secs(5)).await;
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    // the trial fails and opens it again
+    assert!(!p.process(1).await.err().unwrap().is::<CircuitOpen>());
+    assert_eq!(breaker.state(), CircuitState::Open);
+    sleep(Duration::from_secs(5)).await;
+    assert_eq!(p.process(1).await.unwrap(), 1);
+    assert_eq!(breaker.state(), CircuitState::Closed);
+
+    // a trial cancelled by an outer timeout counts as failed instead of holding the trial
+    let breaker = CircuitBreaker::new(1, Duration::from_secs(5));
+    let p = begin::<u64>().then_async_result(|ms| async move {
+        sleep(Duration::from_millis(ms)).await;
+        match ms {
+            0 => Err("zero".into()),
+            ms => Ok(ms),
+        }
+    }).circuit_breaker(breaker.clone()).timeout(Duration::from_millis(50));
+    assert!(p.process(0).await.is_err());
+    sleep(Duration::from_secs(5)).await;
+    assert!(p.process(100).await.err().unwrap().is::<tokio::time::error::Elapsed>());
+    assert_eq!(breaker.state(), CircuitState::Open);
+    sleep(Duration::from_secs(5)).await;
+    assert_eq!(p.process(10).await.unwrap(), 10);
+    assert_eq!(breaker.state(), CircuitState::Closed);
+    assert_eq!(RetryPolicy::exponential(Duration::from_millis(100), Duration::from_secs(1), 3).delay(0),
+               Duration::from_millis(100));
+}
+
+
+#[tokio::test(start_paused = true)]
+async fn test_cached() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+    use tokio::time::sleep;
+    use crate::pipeline::link::{begin, Linkable, Pipeline};
+
+    let calls = Arc::new(AtomicU32::new(0));
+    let c = calls.clone();
+    let p = begin::<u32>().then_async_result(move |x| {
+        let c = c.clone();
+        async move {
+            c.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_millis(100)).await;
+            match x {
+                0 => Err("zero".into()),
+                x => Ok(x * 2),
+            }
+        }
+    }).cached(|x| *x, Duration::from_secs(10), 2);
+
+    // concurrent misses run the stage once
+    let (a, b, c) = tokio::join!(p.process(1), p.process(1), p.process(1));
+    assert_eq!((a.unwrap(), b.unwrap(), c.unwrap()), (2, 2, 2));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(p.process(1).await.unwrap(), 2);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    assert!(p.process(0).await.is_err());
+    assert!(p.process(0).await.is_err());
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // 1 was read last, 2 is evicted by 3
+    p.process(2).await.unwrap();
+    p.process(1).await.unwrap();
+    p.process(3).await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 5);
+    p.process(1).await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 5);
+    p.process(2).await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 6);
+
+    sleep(Duration::from_secs(11)).await;
+    p.process(2).await.unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 7);
+
+    // a leader cancelled by a timeout leaves no flight behind and the waiters run the stage
+    let p = begin::<u32>().then_async(|x| async move {
+        sleep(Duration::from_millis(100)).await;
+        x
+    }).cached(|x| *x, Duration::from_secs(10), 2);
+    let leader = tokio::time::timeout(Duration::from_millis(50), p.process(1));
+    let (cancelled, waiter) = tokio::join!(leader, p.process(1));
+    assert!(cancelled.is_err());
+    assert_eq!(waiter.unwrap(), 1);
+    assert!(p.flights.lock().unwrap().is_empty());
+    let cancelled = tokio::time::timeout(Duration::from_millis(50), p.process(2)).await;
+    assert!(cancelled.is_err());
+    assert!(p.flights.lock().unwrap().is_empty());
+}
+
+
+#[tokio::test]
+async fn test_boxed() {
+    use crate::pipeline::boxed::{BoxedPipeline, Chain};
+    use crate::pipeline::link::{begin, Linkable, Pipeline};
+
+    fn parse(radix: u32) -> BoxedPipeline<String, i64> {
+        begin::<String>().then_result(move |s| Ok(i64::from_str_radix(&s, radix)?)).boxed()
+    }
+    struct Service {
+        parse: BoxedPipeline<String, i64>,
+    }
+    let service = Service { parse: parse(16) };
+    assert_eq!(service.parse.process("ff".to_string()).await.unwrap(), 255);
+    let p = parse(10).then(|x| x + 1).boxed().then(|x| x.to_string());
+    assert_eq!(p.process("41".to_string()).await.unwrap(), "42");
+
+    let mut plugins = Chain::new().stage(begin::<i64>().then(|x| x * 2));
+    assert_eq!(plugins.process(5).await.unwrap(), 10);
+    for add in [1, 2] {
+        plugins.push(begin::<i64>().then_async(move |x| async move { x + add }));
+    }
+    plugins.push(begin::<i64>().then_result(|x| if x < 0 { Err("negative".into()) } else { Ok(x) }));
+    assert_eq!(plugins.len(), 4);
+    assert_eq!(plugins.process(5).await.unwrap(), 13);
+    assert_eq!(plugins.process(-5).await.err().unwrap().to_string(), "stage 0: negative");
+    let p = parse(10).join((plugins, begin::<i64>().then(|x| -x)));
+    assert_eq!(p.process("1".to_string()).await.unwrap(), (5, -1));
+    assert_eq!(Chain::<i64>::new().process(7).await.unwrap(), 7);
+}
+
+
+#[tokio::test]
+async fn test_pipe() {
+    use crate::pipeline::link::{begin, Linkable, Pipeline};
+
+    let trim = || begin::<String>().then(|s| s.trim().to_string());
+    let parse = || begin::<String>().then_result(|s| Ok(s.parse::<i32>()?));
+    let p = begin::<&str>().then(|s| s.to_string()).pipe(trim()).pipe(parse()).then(|x| x + 1);
+    assert_eq!(p.process(" 41 ").await.unwrap(), 42);
+    assert!(p.process("x").await.is_err());
+    // two pipelines end to end
+    let p = trim().pipe(parse());
+    assert_eq!(p.process(" 7".to_string()).await.unwrap(), 7);
+    let p = begin::<String>().pipe(trim());
+    assert_eq!(p.process(" a ".to_string()).await.unwrap(), "a");
+}
+
+
+#[tokio::test]
+async fn test_describe() {
+    use crate::pipeline::link::{begin, Linkable, Pipeline};
+    use crate::pipeline::stage::{cause, StageError};
+
+    let p = begin::<&'static str>()
+        .then_result_named("parse", |s| Ok(s.parse::<i32>()?))
+        .then(|x| x + 1)
+        .branch(|x| *x > 0,
+                begin::<i32>().then_async_result_named("load_user", |x| async move {
+                    if x == 42 { Err("no such user".into()) } else { Ok(x.to_string()) }
+                }),
+                begin::<i32>().then(|_| "nobody".to_string()))
+        .join((begin::<String>().then(|s| s.len()), begin::<String>().then(|s| s)))
+        .map_err(|e| e);
+    assert_eq!(p.describe(), "\
+0 parse (then_result)
+1 then
+2 branch
+  then:
+    0 load_user (then_async_result)
+  otherwise:
+    0 then
+3 join
+  fork 0:
+    0 then
+  fork 1:
+    0 then
+4 map_err
+");
+
+    let err = p.process("x").await.err().unwrap();
+    let stage = err.downcast_ref::<StageError>().unwrap();
+    assert_eq!((stage.index, stage.name), (0, Some("parse")));
+    assert!(cause::<std::num::ParseIntError>(&err).is_some());
+    assert_eq!(p.process("41").await.err().unwrap().to_string(), "stage 0 (load_user): no such user");
+    assert_eq!(p.process("1").await.unwrap(), (1, "2".to_string()));
+
+    let dot = p.dot();
+    assert!(dot.starts_with("digraph pipeline {"));
+    for edge in ["input -> s0;", "s0 -> s1;", "s1 -> s2;", "s2 -> s2_0_s0 [label=\"then\"];",
+        "s2_0_s0 -> s3;", "s2_1_s0 -> s3;", "s3 -> s3_0_s0 [label=\"fork 0\"];",
+        "s3_0_s0 -> s4;", "s3 -> s3_1_s0 [label=\"fork 1\"];", "s3_1_s0 -> s4;", "s4 -> output;"] {
+        assert!(dot.contains(edge), "{} not in {}", edge, dot);
+    }
+    assert!(dot.contains("s2_0_s0 [label=\"0 load_user (then_async_result)\"];"));
+}